@@ -4,6 +4,7 @@ use instant::Instant;
 use crate::{
     character_controller::{GroundEvent, Grounded},
     ghost::Ghost,
+    settings::Settings,
     timing::MapDuration,
 };
 
@@ -43,11 +44,12 @@ pub fn play_finish_sounds(
     mut sound_status: ResMut<SoundsStatus>,
     asset_server: Res<AssetServer>,
     mut commands: Commands,
+    settings: Res<Settings>,
 ) {
     if sound_status.can_play_finish {
         commands.spawn(AudioBundle {
             source: asset_server.load("finish_sound.ogg"),
-            ..default()
+            settings: settings.effect(),
         });
         sound_status.can_play_finish = false;
     }
@@ -58,11 +60,12 @@ pub fn play_character_sounds(
     mut commands: Commands,
     mut er: EventReader<GroundEvent>,
     asset_server: Res<AssetServer>,
+    settings: Res<Settings>,
 ) {
     for _ in er.read() {
         commands.spawn(AudioBundle {
             source: asset_server.load("landing_sound.ogg"),
-            ..default()
+            settings: settings.effect(),
         });
     }
 }
@@ -0,0 +1,391 @@
+//! Input actions and the keys, mouse buttons and gamepad buttons they are bound to.
+
+use std::collections::BTreeMap;
+
+use bevy::{
+    ecs::system::SystemParam,
+    input::gamepad::{GamepadAxis, GamepadAxisType, GamepadButton, GamepadButtonType, Gamepads},
+    prelude::*,
+};
+use bevy_egui::{
+    egui::{self, Color32, Frame, Margin},
+    EguiContexts,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    storage::{self, StorageErrors},
+    ui::show_storage_errors,
+    State,
+};
+
+const BINDINGS_FILE: &str = "bindings.json";
+
+pub struct BindingsPlugin;
+
+impl Plugin for BindingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InputBindings>()
+            .add_systems(Startup, load_bindings)
+            .add_systems(Update, ui_controls.run_if(in_state(State::Controls)));
+    }
+}
+
+/// Something the player can do, independent of what it is bound to.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+pub enum InputAction {
+    MoveForward,
+    MoveBack,
+    MoveLeft,
+    MoveRight,
+    Jump,
+    /// Back to the last checkpoint.
+    Reset,
+    Pause,
+    /// Start the map over.
+    Restart,
+    /// Release or grab the cursor.
+    ToggleCameraLock,
+    /// Show or hide the path trails of the run and the ghosts.
+    ToggleTrails,
+    /// Switch to the next camera mode.
+    CycleCamera,
+}
+
+impl InputAction {
+    pub const ALL: [InputAction; 11] = [
+        InputAction::MoveForward,
+        InputAction::MoveBack,
+        InputAction::MoveLeft,
+        InputAction::MoveRight,
+        InputAction::Jump,
+        InputAction::Reset,
+        InputAction::Pause,
+        InputAction::Restart,
+        InputAction::ToggleCameraLock,
+        InputAction::ToggleTrails,
+        InputAction::CycleCamera,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            InputAction::MoveForward => "Forward",
+            InputAction::MoveBack => "Back",
+            InputAction::MoveLeft => "Left",
+            InputAction::MoveRight => "Right",
+            InputAction::Jump => "Jump",
+            InputAction::Reset => "Reset to checkpoint",
+            InputAction::Pause => "Pause",
+            InputAction::Restart => "Restart",
+            InputAction::ToggleCameraLock => "Toggle camera lock",
+            InputAction::ToggleTrails => "Toggle path trails",
+            InputAction::CycleCamera => "Cycle camera mode",
+        }
+    }
+
+    fn default_bindings(self) -> Vec<Binding> {
+        match self {
+            InputAction::MoveForward => vec![
+                Binding::Key(KeyCode::W),
+                Binding::Key(KeyCode::Up),
+                Binding::Gamepad(GamepadButtonType::DPadUp),
+            ],
+            InputAction::MoveBack => vec![
+                Binding::Key(KeyCode::S),
+                Binding::Key(KeyCode::Down),
+                Binding::Gamepad(GamepadButtonType::DPadDown),
+            ],
+            InputAction::MoveLeft => vec![
+                Binding::Key(KeyCode::A),
+                Binding::Key(KeyCode::Left),
+                Binding::Gamepad(GamepadButtonType::DPadLeft),
+            ],
+            InputAction::MoveRight => vec![
+                Binding::Key(KeyCode::D),
+                Binding::Key(KeyCode::Right),
+                Binding::Gamepad(GamepadButtonType::DPadRight),
+            ],
+            InputAction::Jump => vec![
+                Binding::Key(KeyCode::Space),
+                Binding::Gamepad(GamepadButtonType::South),
+            ],
+            InputAction::Reset => vec![
+                Binding::Key(KeyCode::Back),
+                Binding::Gamepad(GamepadButtonType::West),
+            ],
+            InputAction::Pause => vec![
+                Binding::Key(KeyCode::Escape),
+                Binding::Gamepad(GamepadButtonType::Start),
+            ],
+            InputAction::Restart => vec![
+                Binding::Key(KeyCode::Delete),
+                Binding::Gamepad(GamepadButtonType::Select),
+            ],
+            InputAction::ToggleCameraLock => vec![Binding::Key(KeyCode::Comma)],
+            InputAction::ToggleTrails => vec![Binding::Key(KeyCode::T)],
+            InputAction::CycleCamera => vec![
+                Binding::Key(KeyCode::C),
+                Binding::Gamepad(GamepadButtonType::North),
+            ],
+        }
+    }
+}
+
+/// A key or button an [`InputAction`] can be bound to.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    Gamepad(GamepadButtonType),
+}
+
+impl Binding {
+    fn label(self) -> String {
+        match self {
+            Binding::Key(key) => format!("{key:?}"),
+            Binding::Mouse(button) => format!("Mouse {button:?}"),
+            Binding::Gamepad(button) => format!("Pad {button:?}"),
+        }
+    }
+}
+
+/// The bindings of every action, loaded from `bindings.json` in the data directory.
+#[derive(Resource, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputBindings {
+    pub actions: BTreeMap<InputAction, Vec<Binding>>,
+    /// Camera rotation at full right stick deflection, in radians per second.
+    pub stick_look_speed: f32,
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        Self {
+            actions: InputAction::ALL
+                .into_iter()
+                .map(|action| (action, action.default_bindings()))
+                .collect(),
+            stick_look_speed: 3.,
+        }
+    }
+}
+
+impl InputBindings {
+    /// Binds actions the file doesn't know about yet to their defaults.
+    fn fill_missing(&mut self) {
+        for action in InputAction::ALL {
+            self.actions
+                .entry(action)
+                .or_insert_with(|| action.default_bindings());
+        }
+    }
+
+    pub fn bindings(&self, action: InputAction) -> &[Binding] {
+        self.actions.get(&action).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Reads [`InputAction`]s from the keyboard, mouse and every connected gamepad.
+#[derive(SystemParam)]
+pub struct Actions<'w> {
+    bindings: Res<'w, InputBindings>,
+    keys: Res<'w, Input<KeyCode>>,
+    mouse: Res<'w, Input<MouseButton>>,
+    gamepads: Res<'w, Gamepads>,
+    buttons: Res<'w, Input<GamepadButton>>,
+    axes: Res<'w, Axis<GamepadAxis>>,
+}
+
+impl Actions<'_> {
+    fn check(&self, action: InputAction, pressed: impl Fn(Binding) -> bool) -> bool {
+        self.bindings
+            .bindings(action)
+            .iter()
+            .any(|&binding| pressed(binding))
+    }
+
+    pub fn pressed(&self, action: InputAction) -> bool {
+        self.check(action, |binding| match binding {
+            Binding::Key(key) => self.keys.pressed(key),
+            Binding::Mouse(button) => self.mouse.pressed(button),
+            Binding::Gamepad(button) => self
+                .gamepads
+                .iter()
+                .any(|gamepad| self.buttons.pressed(GamepadButton::new(gamepad, button))),
+        })
+    }
+
+    pub fn just_pressed(&self, action: InputAction) -> bool {
+        self.check(action, |binding| match binding {
+            Binding::Key(key) => self.keys.just_pressed(key),
+            Binding::Mouse(button) => self.mouse.just_pressed(button),
+            Binding::Gamepad(button) => self.gamepads.iter().any(|gamepad| {
+                self.buttons
+                    .just_pressed(GamepadButton::new(gamepad, button))
+            }),
+        })
+    }
+
+    /// Sum of a stick over all gamepads, each axis in -1..=1.
+    fn stick(&self, x: GamepadAxisType, y: GamepadAxisType) -> Vec2 {
+        let mut stick = Vec2::ZERO;
+        for gamepad in self.gamepads.iter() {
+            let axis = |axis_type| {
+                self.axes
+                    .get(GamepadAxis::new(gamepad, axis_type))
+                    .unwrap_or(0.)
+            };
+            stick += Vec2::new(axis(x), axis(y));
+        }
+        stick.clamp(Vec2::NEG_ONE, Vec2::ONE)
+    }
+
+    /// Movement direction with x to the right and y forward, at most 1 long.
+    /// Keys give full length, the left stick anything in between.
+    pub fn movement(&self) -> Vec2 {
+        let horizontal =
+            self.pressed(InputAction::MoveRight) as i8 - self.pressed(InputAction::MoveLeft) as i8;
+        let vertical = self.pressed(InputAction::MoveForward) as i8
+            - self.pressed(InputAction::MoveBack) as i8;
+
+        let keys = Vec2::new(horizontal as f32, vertical as f32);
+        let stick = self.stick(GamepadAxisType::LeftStickX, GamepadAxisType::LeftStickY);
+        (keys + stick).clamp_length_max(1.)
+    }
+
+    /// Camera yaw and pitch change from the right stick this frame, in radians.
+    /// Positive x turns right, positive y looks up.
+    pub fn look(&self, delta_seconds: f32) -> Vec2 {
+        self.stick(GamepadAxisType::RightStickX, GamepadAxisType::RightStickY)
+            * self.bindings.stick_look_speed
+            * delta_seconds
+    }
+}
+
+fn load_bindings(mut bindings: ResMut<InputBindings>, mut errors: ResMut<StorageErrors>) {
+    #[cfg(target_arch = "wasm32")]
+    return;
+
+    *bindings = storage::load_json_or_default(BINDINGS_FILE, &mut errors);
+    bindings.fill_missing();
+}
+
+/// The first key or button pressed this frame, to bind it.
+fn pressed_binding(
+    keys: &Input<KeyCode>,
+    mouse: &Input<MouseButton>,
+    buttons: &Input<GamepadButton>,
+) -> Option<Binding> {
+    keys.get_just_pressed()
+        .next()
+        .map(|&key| Binding::Key(key))
+        .or_else(|| {
+            mouse
+                .get_just_pressed()
+                .next()
+                .map(|&button| Binding::Mouse(button))
+        })
+        .or_else(|| {
+            buttons
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Gamepad(button.button_type))
+        })
+}
+
+/// Lists the bindings of every action. Clicking a binding removes it,
+/// "Add" waits for the next key or button.
+fn ui_controls(
+    mut contexts: EguiContexts,
+    mut state: ResMut<NextState<State>>,
+    mut bindings: ResMut<InputBindings>,
+    mut errors: ResMut<StorageErrors>,
+    keys: Res<Input<KeyCode>>,
+    mouse: Res<Input<MouseButton>>,
+    buttons: Res<Input<GamepadButton>>,
+    mut listening: Local<Option<InputAction>>,
+) {
+    if let Some(action) = *listening {
+        if keys.just_pressed(KeyCode::Escape) {
+            *listening = None;
+        } else if let Some(binding) = pressed_binding(&keys, &mouse, &buttons) {
+            let action_bindings = bindings.actions.entry(action).or_default();
+            if !action_bindings.contains(&binding) {
+                action_bindings.push(binding);
+            }
+            *listening = None;
+        }
+    }
+
+    let mut back = false;
+
+    let ctx = contexts.ctx_mut();
+    egui::Area::new("controls").show(ctx, |ui| {
+        Frame {
+            outer_margin: Margin::symmetric(300., 0.),
+            inner_margin: Margin::same(20.),
+            fill: Color32::from_rgba_unmultiplied(255, 255, 255, 150),
+            ..Default::default()
+        }
+        .show(ui, |ui| {
+            ui.vertical_centered(|ui| {
+                ui.heading("Controls");
+
+                egui::Grid::new("bindings").striped(true).show(ui, |ui| {
+                    for action in InputAction::ALL {
+                        ui.label(action.label());
+                        ui.horizontal(|ui| {
+                            let action_bindings = bindings.actions.entry(action).or_default();
+                            let mut removed = None;
+                            for (i, binding) in action_bindings.iter().enumerate() {
+                                if ui
+                                    .button(binding.label())
+                                    .on_hover_text("Click to remove")
+                                    .clicked()
+                                {
+                                    removed = Some(i);
+                                }
+                            }
+                            if let Some(i) = removed {
+                                action_bindings.remove(i);
+                            }
+
+                            if *listening == Some(action) {
+                                ui.label("Press a key or button, Escape to cancel");
+                            } else if ui.button("Add").clicked() {
+                                *listening = Some(action);
+                            }
+                        });
+                        ui.end_row();
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("Right stick look speed:");
+                    ui.add(
+                        egui::DragValue::new(&mut bindings.stick_look_speed)
+                            .clamp_range(0.1..=20.)
+                            .speed(0.05),
+                    );
+                });
+
+                show_storage_errors(ui, &mut errors);
+
+                ui.horizontal(|ui| {
+                    if ui.button("Reset to defaults").clicked() {
+                        *bindings = InputBindings::default();
+                        *listening = None;
+                    }
+                    back = ui.button("Back").clicked();
+                });
+            })
+        })
+    });
+
+    if back {
+        #[cfg(not(target_arch = "wasm32"))]
+        errors.report(storage::save_json(BINDINGS_FILE, &*bindings));
+        *listening = None;
+        state.set(State::Mainscreen);
+    }
+}
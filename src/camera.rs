@@ -11,12 +11,132 @@ use bevy::{
     transform::TransformSystem,
     window::CursorGrabMode,
 };
-use bevy_xpbd_3d::{prelude::*, PhysicsSet};
+use bevy_xpbd_3d::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::{MapEntityMarker, Player};
+use crate::{
+    bindings::{Actions, InputAction},
+    physics::{interpolate_models, InterpolatedModel, InterpolatedPosition},
+    settings::Settings,
+    storage::StorageErrors,
+    MapEntityMarker,
+};
 
 pub const RADIANS_PER_DOT: f32 = 1.0 / 180.0;
 
+/// Height of the first person camera above the leash.
+const EYE_HEIGHT: f32 = 1.5;
+
+/// Below this horizontal speed the chase camera stops turning with the velocity.
+const CHASE_MIN_SPEED: f32 = 2.;
+
+/// How the camera follows the [`CameraLeash`], cycled with [`InputAction::CycleCamera`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum CameraMode {
+    /// Circles the leash with the mouse.
+    #[default]
+    Orbit,
+    /// Like orbit, but turns behind the direction of the velocity.
+    Chase,
+    /// From the eyes of the leash, its model is hidden.
+    FirstPerson,
+}
+
+impl CameraMode {
+    pub const ALL: [CameraMode; 3] = [
+        CameraMode::Orbit,
+        CameraMode::Chase,
+        CameraMode::FirstPerson,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            CameraMode::Orbit => "Orbit",
+            CameraMode::Chase => "Chase",
+            CameraMode::FirstPerson => "First person",
+        }
+    }
+
+    fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|&mode| mode == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct CameraModeSettings {
+    /// Distance to the leash, shorter where the view is blocked. Unused in first person.
+    pub distance: f32,
+    /// Vertical field of view in degrees.
+    pub fov: f32,
+    /// Seconds the camera lags behind where it should be, 0 follows right away.
+    pub smoothing: f32,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraSettings {
+    pub mode: CameraMode,
+    pub orbit: CameraModeSettings,
+    pub chase: CameraModeSettings,
+    pub first_person: CameraModeSettings,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            mode: CameraMode::Orbit,
+            orbit: CameraModeSettings {
+                distance: 30.,
+                fov: 45.,
+                smoothing: 0.,
+            },
+            chase: CameraModeSettings {
+                distance: 12.,
+                fov: 60.,
+                smoothing: 0.25,
+            },
+            first_person: CameraModeSettings {
+                distance: 0.,
+                fov: 90.,
+                smoothing: 0.,
+            },
+        }
+    }
+}
+
+impl CameraSettings {
+    pub fn get(&self, mode: CameraMode) -> &CameraModeSettings {
+        match mode {
+            CameraMode::Orbit => &self.orbit,
+            CameraMode::Chase => &self.chase,
+            CameraMode::FirstPerson => &self.first_person,
+        }
+    }
+
+    pub fn get_mut(&mut self, mode: CameraMode) -> &mut CameraModeSettings {
+        match mode {
+            CameraMode::Orbit => &mut self.orbit,
+            CameraMode::Chase => &mut self.chase,
+            CameraMode::FirstPerson => &mut self.first_person,
+        }
+    }
+
+    /// Settings of the current mode.
+    pub fn current(&self) -> &CameraModeSettings {
+        self.get(self.mode)
+    }
+}
+
+/// Part of the way to its target a smoothed value covers this frame.
+fn smoothing_factor(smoothing: f32, delta_seconds: f32) -> f32 {
+    if smoothing <= 0. {
+        1.
+    } else {
+        1. - (-delta_seconds / smoothing).exp()
+    }
+}
+
 #[derive(Bundle)]
 pub struct LeashedCameraBundle {
     pub camera: LeashedCamera,
@@ -55,7 +175,7 @@ impl From<bool> for IgnoreMouseInput {
 }
 
 #[derive(Component, Debug)]
-pub struct CameraDistance(f32);
+pub struct CameraDistance(pub f32);
 
 #[derive(Component)]
 pub struct CameraLeash;
@@ -84,10 +204,17 @@ impl Plugin for LeashedCameraPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(
             PostUpdate,
-            (leash_camera, toggle_camera_lock, raycast_camera)
-                .after(PhysicsSet::Sync)
+            (
+                cycle_camera_mode,
+                leash_camera,
+                toggle_camera_lock,
+                raycast_camera,
+                hide_first_person_model,
+            )
+                .chain()
+                .after(interpolate_models)
                 .before(TransformSystem::TransformPropagate)
-                .run_if(in_state(crate::State::Playing)),
+                .run_if(in_state(crate::State::Playing).or_else(in_state(crate::State::Replay))),
         );
     }
 }
@@ -123,12 +250,12 @@ pub fn spawn_camera(commands: &mut Commands, yaw: f32) {
 }
 
 fn toggle_camera_lock(
-    key_input: Res<Input<KeyCode>>,
+    actions: Actions,
     mut windows: Query<&mut Window>,
     mut cameras: Query<&mut IgnoreMouseInput, With<LeashedCamera>>,
 ) {
     let mut window = windows.single_mut();
-    if key_input.just_pressed(KeyCode::Comma) {
+    if actions.just_pressed(InputAction::ToggleCameraLock) {
         if !window.cursor.visible || !window.focused {
             window.cursor.grab_mode = CursorGrabMode::None;
             window.cursor.visible = true;
@@ -143,13 +270,30 @@ fn toggle_camera_lock(
     }
 }
 
+fn cycle_camera_mode(
+    actions: Actions,
+    mut settings: ResMut<Settings>,
+    mut errors: ResMut<StorageErrors>,
+) {
+    if actions.just_pressed(InputAction::CycleCamera) {
+        settings.camera.mode = settings.camera.mode.next();
+        settings.save(&mut errors);
+    }
+}
+
 fn leash_camera(
-    mut player: Query<(&mut RayCaster, &Transform), (With<CameraLeash>, Without<Camera3d>)>,
+    mut player: Query<
+        (&mut RayCaster, &Transform, Option<&LinearVelocity>),
+        (With<CameraLeash>, Without<Camera3d>),
+    >,
     mut cameras: Query<(&mut LeashedCamera, &IgnoreMouseInput, &CameraDistance), With<Camera3d>>,
     mut mouse_events: EventReader<MouseMotion>,
+    actions: Actions,
+    time: Res<Time>,
+    settings: Res<Settings>,
 ) {
     if let Ok(player) = player.get_single_mut() {
-        let (mut raycaster, leash_transform) = player;
+        let (mut raycaster, leash_transform, velocity) = player;
 
         let mut leash_translation_offset = leash_transform.translation;
         leash_translation_offset.y += 1.5;
@@ -158,29 +302,66 @@ fn leash_camera(
         for mouse_event in mouse_events.read() {
             mouse_delta += mouse_event.delta;
         }
+        let mut stick = actions.look(time.delta_seconds());
+        if settings.invert_y {
+            mouse_delta.y = -mouse_delta.y;
+            stick.y = -stick.y;
+        }
 
+        let mode = settings.camera.mode;
         for (mut camera, ignore_mouse, distance) in &mut cameras {
+            if mode == CameraMode::Chase {
+                // Turns behind the velocity, lagging with the smoothing.
+                let velocity = velocity.map_or(Vec2::ZERO, |velocity| velocity.0.xz());
+                if velocity.length() > CHASE_MIN_SPEED {
+                    let heading = velocity.x.atan2(velocity.y);
+                    let turn = (heading - camera.yaw + PI).rem_euclid(2. * PI) - PI;
+                    let smoothing = settings.camera.chase.smoothing;
+                    camera.yaw += turn * smoothing_factor(smoothing, time.delta_seconds());
+                }
+            }
+
             if ignore_mouse.0 {
                 continue;
             }
 
-            let sensitivity = 0.1;
-            camera.pitch = (camera.pitch - mouse_delta.y * RADIANS_PER_DOT * sensitivity)
+            let sensitivity = settings.mouse_sensitivity;
+            camera.pitch = (camera.pitch - mouse_delta.y * RADIANS_PER_DOT * sensitivity + stick.y)
                 .clamp(-PI / 2., PI / 2.);
-            camera.yaw -= mouse_delta.x * RADIANS_PER_DOT * sensitivity;
+            camera.yaw -= mouse_delta.x * RADIANS_PER_DOT * sensitivity + stick.x;
 
-            raycaster.direction = Quat::from_rotation_x(-camera.pitch) * vec3(0., 0., -distance.0);
+            // In first person nothing can block the view.
+            if mode != CameraMode::FirstPerson {
+                raycaster.direction =
+                    Quat::from_rotation_x(-camera.pitch) * vec3(0., 0., -distance.0);
+            }
         }
     }
 }
 
 fn raycast_camera(
-    player: Query<(&Transform, Option<&RayHits>), (With<RayCaster>, With<Player>)>,
+    player: Query<(&InterpolatedPosition, Option<&RayHits>), (With<RayCaster>, With<CameraLeash>)>,
     has_sensor: Query<Has<Sensor>>,
-    mut camera: Query<(&mut Transform, &CameraDistance, &LeashedCamera), Without<Player>>,
+    mut camera: Query<(&mut Transform, &CameraDistance, &LeashedCamera), Without<CameraLeash>>,
+    settings: Res<Settings>,
+    time: Res<Time>,
 ) {
-    for (transform, hits) in &player {
+    let smoothing = smoothing_factor(settings.camera.current().smoothing, time.delta_seconds());
+
+    for (position, hits) in &player {
         if let Ok((mut camera, distance, leashed_camera)) = camera.get_single_mut() {
+            if settings.camera.mode == CameraMode::FirstPerson {
+                let eye = position.rendered + Vec3::Y * EYE_HEIGHT;
+                camera.translation = camera.translation.lerp(eye, smoothing);
+                camera.rotation = Quat::from_euler(
+                    EulerRot::YXZ,
+                    leashed_camera.yaw + PI,
+                    leashed_camera.pitch,
+                    0.,
+                );
+                continue;
+            }
+
             let mut dist = 1.0;
             if let Some(hits) = hits {
                 for hit in hits.iter_sorted() {
@@ -194,8 +375,30 @@ fn raycast_camera(
 
             let rot =
                 Quat::from_euler(EulerRot::YXZ, leashed_camera.yaw, -leashed_camera.pitch, 0.);
-            camera.translation = transform.translation + rot * vec3(0., 0., -(distance.0 * dist));
-            camera.look_at(transform.translation, Vec3::Y);
+            let target = position.rendered + rot * vec3(0., 0., -(distance.0 * dist));
+            camera.translation = camera.translation.lerp(target, smoothing);
+            camera.look_at(position.rendered, Vec3::Y);
+        }
+    }
+}
+
+/// Hides the model of the leash in first person, so it doesn't block the view.
+fn hide_first_person_model(
+    settings: Res<Settings>,
+    leashes: Query<(), With<CameraLeash>>,
+    mut models: Query<(&Parent, &mut Visibility), With<InterpolatedModel>>,
+) {
+    let first_person = settings.camera.mode == CameraMode::FirstPerson;
+    for (parent, mut visibility) in &mut models {
+        let hidden = first_person && leashes.contains(parent.get());
+        let wanted = if hidden {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        };
+        // Only written when it changes, to keep change detection quiet.
+        if *visibility != wanted {
+            *visibility = wanted;
         }
     }
 }
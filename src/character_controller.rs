@@ -1,8 +1,21 @@
-use bevy::{ecs::query::Has, prelude::*};
-use bevy_xpbd_3d::{math::*, prelude::*, SubstepSchedule, SubstepSet};
+use bevy::{
+    ecs::query::{Has, WorldQuery},
+    prelude::*,
+};
+use bevy_xpbd_3d::{math::*, prelude::*, PhysicsSet, SubstepSchedule, SubstepSet};
 use instant::Duration;
-
-use crate::{camera::LeashedCamera, physics::PhysicsLayers, timing::MapDuration};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bindings::{Actions, InputAction, InputBindings},
+    camera::LeashedCamera,
+    physics::PhysicsLayers,
+    profile::MovementProfile,
+    replay::ReplayPlayback,
+    surface::Surface,
+    timing::MapDuration,
+    Player,
+};
 
 pub struct CharacterControllerPlugin;
 
@@ -11,28 +24,48 @@ pub struct CharacterControllerPlugin;
 
 impl Plugin for CharacterControllerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<MovementAction>()
-            .add_event::<GroundEvent>()
-            .add_systems(PreUpdate, tick_cooldown)
+        // The controller runs on the fixed timestep together with physics,
+        // so every tick sees the same delta time regardless of the frame rate.
+        app.add_event::<GroundEvent>()
+            .init_resource::<PendingInput>()
+            .init_resource::<InputBindings>()
+            .configure_sets(
+                FixedUpdate,
+                (ControllerSet::Input, ControllerSet::Movement)
+                    .chain()
+                    .before(PhysicsSet::Prepare),
+            )
+            .add_systems(Update, player_input.run_if(in_state(crate::State::Playing)))
             .add_systems(
-                Update,
+                FixedUpdate,
+                apply_pending_input
+                    .in_set(ControllerSet::Input)
+                    .run_if(in_state(crate::State::Playing)),
+            )
+            .add_systems(
+                FixedUpdate,
                 (
-                    keyboard_input,
+                    tick_cooldown,
                     update_grounded,
                     apply_deferred,
                     apply_gravity,
                     movement,
+                    apply_ground_forces,
                     apply_movement_damping,
+                    soft_cap_speed,
                     decay_multiplier,
                     delayed_reset,
+                    update_animation_state,
                 )
                     .chain()
+                    .in_set(ControllerSet::Movement)
                     .run_if(in_state(crate::State::Playing)),
             )
             .add_systems(
-                Update,
+                FixedUpdate,
                 (update_grounded, apply_deferred, apply_gravity)
                     .chain()
+                    .in_set(ControllerSet::Movement)
                     .run_if(in_state(crate::State::Finished)),
             )
             .add_systems(
@@ -43,10 +76,31 @@ impl Plugin for CharacterControllerPlugin {
     }
 }
 
-#[derive(Event)]
+/// Order of the controller systems within a simulation tick.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ControllerSet {
+    /// Fills the [`TickInput`] of every controller.
+    Input,
+    /// Applies the [`TickInput`] and moves the controllers.
+    Movement,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum MovementAction {
+    /// Move in a direction relative to the camera yaw, X is right and Z is forward.
     Move(Vector3),
     Jump,
+    /// Return to the last checkpoint's [`crate::input::ResetSnapshot`].
+    Reset,
+}
+
+/// The actions a controller performs on the current simulation tick.
+/// Filled from the keyboard, or from a replay when one is re-simulated.
+#[derive(Component, Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TickInput {
+    /// Yaw of the camera that [`MovementAction::Move`] is relative to.
+    pub yaw: Scalar,
+    pub actions: Vec<MovementAction>,
 }
 
 #[derive(Event)]
@@ -55,6 +109,21 @@ pub enum GroundEvent {
     Reset,
 }
 
+/// Keyboard input collected every frame until the next simulation tick consumes it.
+#[derive(Resource, Default)]
+pub struct PendingInput {
+    direction: Vector3,
+    jump: bool,
+    reset: bool,
+}
+
+impl PendingInput {
+    /// Resets the player to the last checkpoint on the next tick.
+    pub fn request_reset(&mut self) {
+        self.reset = true;
+    }
+}
+
 #[derive(Component)]
 pub struct JumpCount(pub u32);
 
@@ -67,17 +136,59 @@ pub struct CharacterController;
 #[component(storage = "SparseSet")]
 pub struct Grounded;
 
-/// A marker component indicating that an entity is on the ground.
+/// A marker component indicating that an entity is on ground too steep to walk on.
 #[derive(Component)]
 #[component(storage = "SparseSet")]
 pub struct Sliding;
 
+/// A marker component indicating that an entity is neither [`Grounded`] nor [`Sliding`].
+/// Movement input air strafes then, see [`AirControl`].
+#[derive(Component)]
+#[component(storage = "SparseSet")]
+pub struct Airborne;
+
+/// The ground below a character controller, whether it's [`Grounded`] or [`Sliding`] on it.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct GroundContact {
+    /// Points away from the ground, [`Vector::Y`] in the air.
+    pub normal: Vector,
+    pub surface: Option<Surface>,
+}
+
+/// Which clip the model of a character controller plays.
+/// Recorded with replays, so ghosts are animated like the run was.
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct AnimationState {
+    pub ground: GroundState,
+    /// Moving fast enough to gallop.
+    pub moving: bool,
+    /// The [`JumpCount`], every air jump plays the jump clip again.
+    pub jump_count: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum GroundState {
+    Grounded,
+    Sliding,
+    #[default]
+    Airborne,
+}
+
+impl Default for GroundContact {
+    fn default() -> Self {
+        Self {
+            normal: Vector::Y,
+            surface: None,
+        }
+    }
+}
+
 /// The acceleration used for character movement.
 #[derive(Component)]
 pub struct MovementAcceleration(Scalar);
 
 #[derive(Component)]
-pub struct AccelerationMultiplier(Scalar);
+pub struct AccelerationMultiplier(pub Scalar);
 
 /// The damping factor used for slowing down movement.
 #[derive(Component)]
@@ -100,6 +211,34 @@ pub struct MaxSlopeAngle(pub Scalar);
 #[derive(Component)]
 pub struct JumpResetCooldown(pub Timer);
 
+/// How the [`AccelerationMultiplier`] grows with jumps and decays over time.
+#[derive(Component)]
+pub struct MultiplierTuning {
+    jump_bonus: Scalar,
+    ground_decay: Scalar,
+    air_decay: Scalar,
+    max: Scalar,
+}
+
+/// Jumps possible in the air before landing again.
+#[derive(Component)]
+pub struct AirJumps(pub u32);
+
+/// How a controller moves while it's [`Airborne`] or [`Sliding`], see [`MovementProfile`].
+#[derive(Component)]
+pub struct AirControl {
+    acceleration: Scalar,
+    wish_speed: Scalar,
+    damping: Scalar,
+}
+
+/// Horizontal speed above `soft_cap` decays, so momentum can be built but not without bound.
+#[derive(Component)]
+pub struct SpeedCap {
+    soft_cap: Scalar,
+    decay: Scalar,
+}
+
 /// A bundle that contains the components needed for a basic
 /// kinematic character controller.
 #[derive(Bundle)]
@@ -112,6 +251,9 @@ pub struct CharacterControllerBundle {
     movement: MovementBundle,
     jump_count: JumpCount,
     reset_timer: JumpResetCooldown,
+    input: TickInput,
+    ground: GroundContact,
+    animation: AnimationState,
 }
 
 /// A bundle that contains components for character movement.
@@ -122,38 +264,96 @@ pub struct MovementBundle {
     jump_impulse: JumpImpulse,
     max_slope_angle: MaxSlopeAngle,
     multiplier: AccelerationMultiplier,
+    multiplier_tuning: MultiplierTuning,
+    air_jumps: AirJumps,
+    air_control: AirControl,
+    speed_cap: SpeedCap,
 }
 
 impl MovementBundle {
-    pub const fn new(
-        acceleration: Scalar,
-        damping: Scalar,
-        jump_impulse: Scalar,
-        max_slope_angle: Scalar,
-    ) -> Self {
+    pub fn new(profile: &MovementProfile) -> Self {
         Self {
-            acceleration: MovementAcceleration(acceleration),
-            damping: MovementDampingFactor(damping),
-            jump_impulse: JumpImpulse(jump_impulse),
-            max_slope_angle: MaxSlopeAngle(max_slope_angle),
+            acceleration: MovementAcceleration(profile.acceleration),
+            damping: MovementDampingFactor(profile.damping),
+            jump_impulse: JumpImpulse(profile.jump_impulse),
+            max_slope_angle: MaxSlopeAngle(profile.max_slope_angle.to_radians()),
             multiplier: AccelerationMultiplier(1.),
+            multiplier_tuning: MultiplierTuning {
+                jump_bonus: profile.jump_multiplier_bonus,
+                ground_decay: profile.ground_multiplier_decay,
+                air_decay: profile.air_multiplier_decay,
+                max: profile.max_multiplier,
+            },
+            air_jumps: AirJumps(profile.air_jumps),
+            air_control: AirControl {
+                acceleration: profile.air_acceleration,
+                wish_speed: profile.air_wish_speed,
+                damping: profile.air_damping,
+            },
+            speed_cap: SpeedCap {
+                soft_cap: profile.speed_soft_cap,
+                decay: profile.over_cap_decay,
+            },
         }
     }
 }
 
-impl Default for MovementBundle {
-    fn default() -> Self {
-        Self::new(30.0, 0.96, 7.0, PI * 0.45)
+/// The components of a controller that a [`MovementProfile`] sets.
+#[derive(WorldQuery)]
+#[world_query(mutable)]
+pub struct ControllerTunables {
+    acceleration: &'static mut MovementAcceleration,
+    damping: &'static mut MovementDampingFactor,
+    jump_impulse: &'static mut JumpImpulse,
+    max_slope_angle: &'static mut MaxSlopeAngle,
+    gravity: &'static mut ControllerGravity,
+    multiplier_tuning: &'static mut MultiplierTuning,
+    air_jumps: &'static mut AirJumps,
+    air_control: &'static mut AirControl,
+    speed_cap: &'static mut SpeedCap,
+    cooldown: &'static mut JumpResetCooldown,
+}
+
+impl ControllerTunablesItem<'_> {
+    /// Changes the tunables in place, velocity, jumps and the current multiplier are kept.
+    pub fn apply(&mut self, profile: &MovementProfile) {
+        self.acceleration.0 = profile.acceleration;
+        self.damping.0 = profile.damping;
+        self.jump_impulse.0 = profile.jump_impulse;
+        self.max_slope_angle.0 = profile.max_slope_angle.to_radians();
+        self.gravity.0 = Vector::NEG_Y * profile.gravity;
+        *self.multiplier_tuning = MultiplierTuning {
+            jump_bonus: profile.jump_multiplier_bonus,
+            ground_decay: profile.ground_multiplier_decay,
+            air_decay: profile.air_multiplier_decay,
+            max: profile.max_multiplier,
+        };
+        self.air_jumps.0 = profile.air_jumps;
+        *self.air_control = AirControl {
+            acceleration: profile.air_acceleration,
+            wish_speed: profile.air_wish_speed,
+            damping: profile.air_damping,
+        };
+        *self.speed_cap = SpeedCap {
+            soft_cap: profile.speed_soft_cap,
+            decay: profile.over_cap_decay,
+        };
+        self.cooldown
+            .0
+            .set_duration(Duration::from_millis(profile.jump_cooldown_ms));
     }
 }
 
 impl CharacterControllerBundle {
-    pub fn new(collider: Collider, gravity: Vector) -> Self {
+    pub fn new(collider: Collider, profile: &MovementProfile) -> Self {
         // Create shape caster as a slightly smaller version of collider
         let mut caster_shape = collider.clone();
         caster_shape.set_scale(Vector::ONE * 0.99, 10);
 
-        let timer = JumpResetCooldown(Timer::new(Duration::from_millis(300), TimerMode::Once));
+        let timer = JumpResetCooldown(Timer::new(
+            Duration::from_millis(profile.jump_cooldown_ms),
+            TimerMode::Once,
+        ));
 
         Self {
             character_controller: CharacterController,
@@ -166,47 +366,59 @@ impl CharacterControllerBundle {
                 Vector::NEG_Y,
             )
             .with_max_time_of_impact(0.2)
-            .with_max_hits(2),
-            gravity: ControllerGravity(gravity),
-            movement: MovementBundle::default(),
+            .with_max_hits(2)
+            .with_query_filter(SpatialQueryFilter::new().with_masks([PhysicsLayers::Ground])),
+            gravity: ControllerGravity(Vector::NEG_Y * profile.gravity),
+            movement: MovementBundle::new(profile),
             jump_count: JumpCount(0),
             reset_timer: timer,
+            input: TickInput::default(),
+            ground: GroundContact::default(),
+            animation: AnimationState::default(),
         }
     }
+}
+
+/// Collects the bound keys and gamepad input into [`PendingInput`].
+fn player_input(mut pending: ResMut<PendingInput>, actions: Actions) {
+    let movement = actions.movement();
+    pending.direction = Vector3::new(movement.x as Scalar, 0., movement.y as Scalar);
+
+    // Presses are kept until a tick consumes them, otherwise they're lost on frames without a tick.
+    if actions.just_pressed(InputAction::Jump) {
+        pending.jump = true;
+    }
 
-    pub fn with_movement(
-        mut self,
-        acceleration: Scalar,
-        damping: Scalar,
-        jump_impulse: Scalar,
-        max_slope_angle: Scalar,
-    ) -> Self {
-        self.movement = MovementBundle::new(acceleration, damping, jump_impulse, max_slope_angle);
-        self
+    if actions.just_pressed(InputAction::Reset) {
+        pending.reset = true;
     }
 }
 
-/// Sends [`MovementAction`] events based on keyboard input.
-fn keyboard_input(
-    mut movement_event_writer: EventWriter<MovementAction>,
-    keyboard_input: Res<Input<KeyCode>>,
+/// Turns [`PendingInput`] into the player's [`TickInput`] once the run has started.
+fn apply_pending_input(
+    mut pending: ResMut<PendingInput>,
+    mut players: Query<&mut TickInput, (With<Player>, With<MapDuration>, Without<ReplayPlayback>)>,
+    cameras: Query<&LeashedCamera>,
 ) {
-    let up = keyboard_input.any_pressed([KeyCode::W, KeyCode::Up]);
-    let down = keyboard_input.any_pressed([KeyCode::S, KeyCode::Down]);
-    let left = keyboard_input.any_pressed([KeyCode::A, KeyCode::Left]);
-    let right = keyboard_input.any_pressed([KeyCode::D, KeyCode::Right]);
-
-    let horizontal = right as i8 - left as i8;
-    let vertical = up as i8 - down as i8;
-    let direction =
-        Vector3::new(horizontal as Scalar, 0., vertical as Scalar).clamp_length_max(1.0);
-
-    if direction != Vector3::ZERO {
-        movement_event_writer.send(MovementAction::Move(direction));
+    let Ok(mut input) = players.get_single_mut() else {
+        return;
+    };
+
+    input.actions.clear();
+    input.yaw = cameras.get_single().map_or(0., |camera| camera.yaw);
+
+    if pending.direction != Vector3::ZERO {
+        input.actions.push(MovementAction::Move(pending.direction));
+    }
+
+    if pending.jump {
+        input.actions.push(MovementAction::Jump);
+        pending.jump = false;
     }
 
-    if keyboard_input.just_pressed(KeyCode::Space) {
-        movement_event_writer.send(MovementAction::Jump);
+    if pending.reset {
+        input.actions.push(MovementAction::Reset);
+        pending.reset = false;
     }
 }
 
@@ -223,11 +435,14 @@ fn update_grounded(
             Has<Grounded>,
             Has<Sliding>,
             &mut JumpResetCooldown,
+            &mut GroundContact,
+            Has<Player>,
         ),
         With<CharacterController>,
     >,
     mut ew: EventWriter<GroundEvent>,
     layers: Query<&CollisionLayers>,
+    surfaces: Query<&Surface>,
 ) {
     for (
         entity,
@@ -238,6 +453,8 @@ fn update_grounded(
         was_grounded,
         was_sliding,
         mut jump_reset_cd,
+        mut contact,
+        is_player,
     ) in &mut query
     {
         // The character is grounded if the shape caster has a hit with a normal
@@ -245,25 +462,28 @@ fn update_grounded(
         let mut is_sliding = false;
         let mut is_grounded = false;
 
-        _ = hits.iter().any(|hit| {
-            if let Ok(layer) = layers.get(hit.entity) {
-                if !layer.contains_group(PhysicsLayers::Ground) {
-                    return false;
-                }
-            }
+        let ground = hits.iter().find(|hit| {
+            layers
+                .get(hit.entity)
+                .map_or(true, |layer| layer.contains_group(PhysicsLayers::Ground))
+        });
+
+        *contact = GroundContact::default();
+        if let Some(hit) = ground {
+            let normal = rotation.rotate(-hit.normal2);
+            *contact = GroundContact {
+                normal,
+                surface: surfaces.get(hit.entity).ok().copied(),
+            };
 
             if let Some(angle) = max_slope_angle {
-                if rotation.rotate(-hit.normal2).angle_between(Vector::Y).abs() <= angle.0 {
+                if normal.angle_between(Vector::Y).abs() <= angle.0 {
                     is_grounded = true;
-                    true
                 } else {
                     is_sliding = true;
-                    true
                 }
-            } else {
-                true
             }
-        });
+        }
 
         if is_grounded {
             // Try: prevent racecondition when unloading
@@ -278,76 +498,149 @@ fn update_grounded(
             commands.entity(entity).remove::<Sliding>();
         }
 
+        if !is_grounded && !is_sliding {
+            commands.entity(entity).try_insert(Airborne);
+        } else {
+            commands.entity(entity).remove::<Airborne>();
+        }
+
         #[allow(clippy::nonminimal_bool)] // The suggestion is completely unreadable
         // Only emit the event if the "grounded|sliding" status is new
         if ((is_grounded && !was_sliding && !was_grounded)
             || (is_sliding && !was_grounded && !was_sliding))
             && jump_reset_cd.0.finished()
+            // Ghosts shouldn't play landing sounds and effects
+            && is_player
         {
             ew.send(GroundEvent::Grounded(transform.translation));
         }
     }
 }
 
-/// Responds to [`MovementAction`] events and moves character controllers accordingly.
+/// Applies the [`TickInput`] of character controllers and moves them accordingly.
 fn movement(
     time: Res<Time>,
-    mut movement_event_reader: EventReader<MovementAction>,
-    mut controllers: Query<
-        (
-            &MovementAcceleration,
-            &JumpImpulse,
-            &mut LinearVelocity,
-            &mut JumpCount,
-            &mut AccelerationMultiplier,
-            &mut JumpResetCooldown,
-            Has<Grounded>,
-            Has<Sliding>,
-        ),
-        With<MapDuration>,
-    >,
-    cameras: Query<&Transform, With<LeashedCamera>>,
+    mut controllers: Query<(
+        &TickInput,
+        &MovementAcceleration,
+        &JumpImpulse,
+        &MultiplierTuning,
+        &AirJumps,
+        &AirControl,
+        &mut LinearVelocity,
+        &mut JumpCount,
+        &mut AccelerationMultiplier,
+        &mut JumpResetCooldown,
+        Has<Grounded>,
+        Has<Sliding>,
+        Has<Airborne>,
+    )>,
 ) {
     let delta_time = time.delta_seconds();
 
-    if let Ok(camera_transform) = cameras.get_single() {
-        for event in movement_event_reader.read() {
-            for (
-                movement_acceleration,
-                jump_impulse,
-                mut linear_velocity,
-                mut jump_count,
-                mut acc_mul,
-                mut timer,
-                is_grounded,
-                is_sliding,
-            ) in &mut controllers
-            {
-                match event {
-                    MovementAction::Move(mut direction) => {
-                        direction = camera_transform.rotation.inverse().mul_vec3(direction);
+    for (
+        input,
+        movement_acceleration,
+        jump_impulse,
+        tuning,
+        air_jumps,
+        air_control,
+        mut linear_velocity,
+        mut jump_count,
+        mut acc_mul,
+        mut timer,
+        is_grounded,
+        is_sliding,
+        is_airborne,
+    ) in &mut controllers
+    {
+        for action in &input.actions {
+            match action {
+                MovementAction::Move(direction) => {
+                    // Rotate the input into world space using only the camera yaw,
+                    // so the result doesn't depend on the rendered camera transform.
+                    let direction = Quaternion::from_rotation_y(input.yaw + PI)
+                        * Vector3::new(direction.x, 0., -direction.z);
+
+                    if is_grounded {
                         linear_velocity.x +=
                             direction.x * movement_acceleration.0 * acc_mul.0 * delta_time;
-                        linear_velocity.z -=
+                        linear_velocity.z +=
                             direction.z * movement_acceleration.0 * acc_mul.0 * delta_time;
+                    } else if is_airborne || is_sliding {
+                        // Steep slopes are steered like the air.
+                        air_accelerate(
+                            air_control,
+                            direction,
+                            acc_mul.0,
+                            delta_time,
+                            &mut linear_velocity,
+                        );
                     }
-                    MovementAction::Jump => {
-                        if (is_grounded || is_sliding) && timer.0.finished() {
-                            acc_mul.0 += 1.1;
-                            jump_count.0 = 0;
-                            linear_velocity.y = jump_impulse.0;
-                            timer.0.reset();
-                        } else if jump_count.0 < 2 {
-                            jump_count.0 += 1;
-                            linear_velocity.y = jump_impulse.0;
-                        }
+                }
+                MovementAction::Jump => {
+                    if (is_grounded || is_sliding) && timer.0.finished() {
+                        acc_mul.0 += tuning.jump_bonus;
+                        jump_count.0 = 0;
+                        linear_velocity.y = jump_impulse.0;
+                        timer.0.reset();
+                    } else if jump_count.0 < air_jumps.0 {
+                        jump_count.0 += 1;
+                        linear_velocity.y = jump_impulse.0;
                     }
                 }
+                // Handled by `input::reset_to_checkpoint`
+                MovementAction::Reset => {}
             }
         }
     }
 }
 
+/// Quake style air strafing: input only adds speed along its own direction up to the wish speed.
+/// Strafing sideways to the velocity is never limited, so turning along with it builds speed.
+fn air_accelerate(
+    air_control: &AirControl,
+    direction: Vector3,
+    multiplier: Scalar,
+    delta_time: Scalar,
+    linear_velocity: &mut LinearVelocity,
+) {
+    let wish_speed = air_control.wish_speed * direction.length();
+    let wish_direction = direction.normalize_or_zero();
+    let current = Vector3::new(linear_velocity.x, 0., linear_velocity.z).dot(wish_direction);
+    let added =
+        (wish_speed - current).min(air_control.acceleration * wish_speed * multiplier * delta_time);
+    if added > 0. {
+        linear_velocity.x += wish_direction.x * added;
+        linear_velocity.z += wish_direction.z * added;
+    }
+}
+
+fn update_animation_state(
+    mut query: Query<(
+        &LinearVelocity,
+        &JumpCount,
+        Has<Grounded>,
+        Has<Sliding>,
+        &mut AnimationState,
+    )>,
+) {
+    for (linear_velocity, jump_count, is_grounded, is_sliding, mut animation) in &mut query {
+        let ground = if is_grounded {
+            GroundState::Grounded
+        } else if is_sliding {
+            GroundState::Sliding
+        } else {
+            GroundState::Airborne
+        };
+        *animation = AnimationState {
+            ground,
+            moving: linear_velocity.0.length() > 1.,
+            jump_count: jump_count.0,
+        };
+    }
+}
+
 fn tick_cooldown(mut query: Query<&mut JumpResetCooldown>, time: Res<Time>) {
     for mut cd in &mut query {
         cd.0.tick(time.delta());
@@ -376,15 +669,58 @@ fn apply_gravity(
     }
 }
 
-/// Slows down movement in the XZ plane.
+/// Accelerates grounded controllers down slopes and along boost surfaces.
+fn apply_ground_forces(
+    time: Res<Time>,
+    mut controllers: Query<
+        (&ControllerGravity, &GroundContact, &mut LinearVelocity),
+        With<Grounded>,
+    >,
+) {
+    let delta_time = time.delta_seconds();
+
+    for (gravity, contact, mut linear_velocity) in &mut controllers {
+        // Gravity projected onto the ground, its horizontal part points downhill.
+        let along_ground = gravity.0 - contact.normal * gravity.0.dot(contact.normal);
+        let downhill = Vector::new(along_ground.x, 0., along_ground.z);
+        let horizontal = Vector::new(linear_velocity.x, 0., linear_velocity.z);
+
+        // Running downhill gains speed, standing on a walkable slope doesn't start a slide.
+        let is_slippery = contact.surface.is_some_and(Surface::is_slippery);
+        if is_slippery || horizontal.dot(downhill) > 0. {
+            linear_velocity.x += downhill.x * delta_time;
+            linear_velocity.z += downhill.z * delta_time;
+        }
+
+        if let Some(surface) = contact.surface {
+            let direction = horizontal.normalize_or_zero();
+            linear_velocity.x += direction.x * surface.boost() * delta_time;
+            linear_velocity.z += direction.z * surface.boost() * delta_time;
+        }
+    }
+}
+
+/// Slows down movement in the XZ plane, less in the air than on the ground.
+/// On the ground the [`Surface`] scales how much is taken away.
 fn apply_movement_damping(
     time: Res<Time>,
-    mut query: Query<(&MovementDampingFactor, &mut LinearVelocity)>,
+    mut query: Query<(
+        &MovementDampingFactor,
+        &AirControl,
+        &GroundContact,
+        &mut LinearVelocity,
+        Has<Grounded>,
+    )>,
 ) {
     let dt = time.delta_seconds();
-    for (damping_factor, mut linear_velocity) in &mut query {
+    for (damping_factor, air_control, contact, mut linear_velocity, is_grounded) in &mut query {
         // We could use `LinearDamping`, but we don't want to dampen movement along the Y axis
-        let factor = damping_factor.0;
+        let factor = if is_grounded {
+            let friction = contact.surface.map_or(1., Surface::friction);
+            1. - (1. - damping_factor.0) * friction
+        } else {
+            air_control.damping
+        };
         // dbg!(&linear_velocity);
         linear_velocity.x *= factor.powf(dt * 60.);
         linear_velocity.z *= factor.powf(dt * 60.);
@@ -392,15 +728,40 @@ fn apply_movement_damping(
     }
 }
 
+/// Decays horizontal speed above the [`SpeedCap`] towards it.
+fn soft_cap_speed(time: Res<Time>, mut query: Query<(&SpeedCap, &mut LinearVelocity)>) {
+    let dt = time.delta_seconds();
+    for (cap, mut linear_velocity) in &mut query {
+        let horizontal = Vector2::new(linear_velocity.x, linear_velocity.z);
+        let speed = horizontal.length();
+        if speed <= cap.soft_cap {
+            continue;
+        }
+
+        let capped = cap.soft_cap + (speed - cap.soft_cap) * cap.decay.powf(dt * 60.);
+        let horizontal = horizontal * (capped / speed);
+        linear_velocity.x = horizontal.x;
+        linear_velocity.z = horizontal.y;
+    }
+}
+
 /// Slowly decay the acceleration multiplier over time
 fn decay_multiplier(
     time: Res<Time>,
-    mut query: Query<(&mut AccelerationMultiplier, Has<Grounded>)>,
+    mut query: Query<(
+        &mut AccelerationMultiplier,
+        &MultiplierTuning,
+        Has<Grounded>,
+    )>,
 ) {
     let dt = time.delta_seconds();
-    for (mut acc, is_grounded) in &mut query {
-        let decay_factor = (if is_grounded { 0.97 } else { 0.999 } as f32).powf(dt * 60.);
-        acc.0 = (acc.0 * decay_factor).clamp(1.0, 10.);
+    for (mut acc, tuning, is_grounded) in &mut query {
+        let decay = if is_grounded {
+            tuning.ground_decay
+        } else {
+            tuning.air_decay
+        };
+        acc.0 = (acc.0 * decay.powf(dt * 60.)).clamp(1.0, tuning.max);
     }
 }
 
@@ -472,13 +833,67 @@ fn kinematic_controller_collisions(
                 position.0 += normal * contact.penetration;
             }
 
-            // If the slope isn't too steep to walk on but the character
-            // is falling, reset vertical velocity.
-            if max_slope_angle.is_some_and(|angle| normal.angle_between(Vector::Y).abs() <= angle.0)
-                && linear_velocity.y < 0.0
-            {
-                linear_velocity.y = linear_velocity.y.max(0.0);
-            }
+            linear_velocity.0 = collide_velocity(linear_velocity.0, normal, max_slope_angle);
+        }
+    }
+}
+
+/// The velocity of a character controller touching a surface, `normal` points away from it.
+fn collide_velocity(
+    mut velocity: Vector,
+    normal: Vector,
+    max_slope_angle: Option<&MaxSlopeAngle>,
+) -> Vector {
+    if max_slope_angle.is_some_and(|angle| normal.angle_between(Vector::Y).abs() <= angle.0) {
+        // If the slope isn't too steep to walk on but the character
+        // is falling, reset vertical velocity.
+        velocity.y = velocity.y.max(0.0);
+    } else {
+        // Walls, ceilings and steep slopes only keep the velocity along them,
+        // so the character slides off instead of sticking to them.
+        let into_surface = velocity.dot(normal);
+        if into_surface < 0.0 {
+            velocity -= normal * into_surface;
         }
     }
+    velocity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn max_slope() -> MaxSlopeAngle {
+        MaxSlopeAngle(MovementProfile::default().max_slope_angle.to_radians())
+    }
+
+    #[test]
+    fn landing_keeps_horizontal_velocity() {
+        let velocity = collide_velocity(Vector::new(3., -5., 1.), Vector::Y, Some(&max_slope()));
+        assert_eq!(velocity, Vector::new(3., 0., 1.));
+    }
+
+    #[test]
+    fn walls_only_keep_velocity_along_them() {
+        let velocity = collide_velocity(Vector::new(3., 0., 4.), Vector::NEG_X, Some(&max_slope()));
+        assert_eq!(velocity, Vector::new(0., 0., 4.));
+
+        // Moving away from a wall isn't slowed down.
+        let velocity =
+            collide_velocity(Vector::new(-3., 0., 4.), Vector::NEG_X, Some(&max_slope()));
+        assert_eq!(velocity, Vector::new(-3., 0., 4.));
+    }
+
+    #[test]
+    fn steep_slopes_are_slid_along() {
+        let normal = Quaternion::from_rotation_z(60f32.to_radians()) * Vector::Y;
+        let velocity = collide_velocity(Vector::new(0., -10., 0.), normal, Some(&max_slope()));
+
+        assert!(velocity.dot(normal).abs() < 1e-4, "velocity {velocity}");
+        // Falling onto it turns into sliding down and away from it.
+        assert!(
+            velocity.y < 0. && velocity.x * normal.x > 0.,
+            "velocity {velocity}"
+        );
+    }
 }
@@ -1,21 +1,43 @@
+use std::time::Duration;
+
 use bevy::{prelude::*, window::CursorGrabMode};
-use bevy_xpbd_3d::prelude::{
-    contact_query::intersection_test, Collider, CollidingEntities, LinearVelocity,
+use bevy_egui::{
+    egui::{self, Align2, Color32, RichText},
+    EguiContexts,
+};
+use bevy_xpbd_3d::{
+    prelude::{
+        contact_query::intersection_test, Collider, CollisionLayers, LinearVelocity, RigidBody,
+        Sensor,
+    },
+    PhysicsSet,
 };
 
 use crate::{
-    camera::LeashedCamera, character_controller::JumpCount, ghost::GhostOneshots,
-    input::ResetSnapshot, leaderboard::LeaderboardEvent, map::Map, timing::MapDuration, Player,
-    State,
+    camera::LeashedCamera,
+    character_controller::{JumpCount, TickInput},
+    ghost::{GhostDelay, GhostOneshots},
+    input::ResetSnapshot,
+    leaderboard::{Highscore, LeaderboardEvent},
+    map::{self, Map},
+    physics::{store_tick_position, InterpolatedPosition, PhysicsLayers},
+    replay::{ReplayPlayback, ReplayRecorder},
+    splits::Splits,
+    timing::MapDuration,
+    tuning::Tuned,
+    MapEntityMarker, Player, State,
 };
 
 pub struct CheckpointPlugin;
 
 impl Plugin for CheckpointPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            Update,
-            (check_checkpoint, all_checkpoints_reached, on_goal)
+        app.add_event::<WrongCheckpoint>().add_systems(
+            FixedUpdate,
+            (check_checkpoint, on_goal)
+                .chain()
+                .after(PhysicsSet::Sync)
+                .after(store_tick_position)
                 .run_if(in_state(crate::State::Playing)),
         );
     }
@@ -24,61 +46,291 @@ impl Plugin for CheckpointPlugin {
 #[derive(Component)]
 pub struct Checkpoint {
     pub reached: bool,
+    /// Position in [`Map::checkpoints`].
+    pub index: usize,
 }
 
 #[derive(Component)]
 pub struct Goal;
 
-#[derive(Component)]
-#[component(storage = "SparseSet")]
-pub struct AllCheckpointsReached;
+/// Progress of the player through the current lap.
+#[derive(Component, Default, Clone, Copy)]
+pub struct CheckpointProgress {
+    /// Number of checkpoints passed in order. Only used on maps with
+    /// [`Map::ordered_checkpoints`], where it decides which checkpoint is active.
+    pub next: usize,
+    /// Whether the player was inside the goal on the last tick, so a lap only counts once.
+    in_goal: bool,
+}
+
+/// Sent when the player touches a checkpoint ahead of the next one on an ordered map.
+#[derive(Event)]
+pub struct WrongCheckpoint {
+    /// Index of the checkpoint that has to be reached first.
+    pub expected: usize,
+}
+
+pub fn checkpoint_transform(checkpoint: &map::Checkpoint) -> Transform {
+    Transform::from_translation(checkpoint.pos)
+        .with_scale(Vec3::splat(2.))
+        .with_rotation(Quat::from_rotation_y(checkpoint.rot.to_radians()))
+}
+
+/// The simulated part of a checkpoint, without its model or transform.
+pub fn checkpoint_bundle(index: usize) -> impl Bundle {
+    (
+        Sensor,
+        CollisionLayers::new([PhysicsLayers::Sensor], [PhysicsLayers::Sensor]),
+        Collider::cuboid(10., 20., 3.),
+        RigidBody::Static,
+        Checkpoint {
+            reached: false,
+            index,
+        },
+        MapEntityMarker,
+    )
+}
+
+pub fn spawn_checkpoints(commands: &mut Commands, map: &Map, model: &Handle<Scene>) {
+    for (index, checkpoint) in map.checkpoints.iter().enumerate() {
+        commands.spawn((
+            SceneBundle {
+                scene: model.clone_weak(),
+                transform: checkpoint_transform(checkpoint),
+                ..Default::default()
+            },
+            checkpoint_bundle(index),
+        ));
+    }
+}
+
+pub fn goal_transform(map: &Map) -> Transform {
+    Transform::from_translation(map.end_pos)
+        .with_scale(Vec3::splat(3.))
+        .with_rotation(Quat::from_rotation_y(map.end_rotation.to_radians()))
+}
+
+pub fn goal_collider() -> Collider {
+    Collider::cuboid(10., 10., 3.)
+}
 
+/// How far through the latest tick a body first touched a sensor, 0 is the start of the tick and 1 its end.
+/// Its path during the tick is bisected, so crossings are timed more precisely than the tick rate.
+fn crossing_fraction(
+    collider: &Collider,
+    interpolated: Option<&InterpolatedPosition>,
+    rotation: Quat,
+    sensor_collider: &Collider,
+    sensor_transform: &Transform,
+) -> f64 {
+    let Some(interpolated) = interpolated else {
+        return 1.;
+    };
+    let touches = |fraction: f32| {
+        intersection_test(
+            collider,
+            interpolated.during_tick(fraction),
+            rotation,
+            sensor_collider,
+            sensor_transform.translation,
+            sensor_transform.rotation,
+        )
+        .expect("Unsupported intersection shape!")
+    };
+    // Already touching at the start of the tick, e.g. after being reset onto it.
+    if touches(0.) {
+        return 0.;
+    }
+
+    let (mut outside, mut inside) = (0., 1.);
+    for _ in 0..16 {
+        let middle = (outside + inside) / 2.;
+        if touches(middle) {
+            inside = middle;
+        } else {
+            outside = middle;
+        }
+    }
+    inside as f64
+}
+
+/// Takes a [`ResetSnapshot`] when the player is inside a checkpoint.
+/// Runs on the simulation tick, so a replayed run resets to the same snapshot the recorded one did.
 pub fn check_checkpoint(
     mut commands: Commands,
-    mut query: Query<(&CollidingEntities, &mut Checkpoint)>,
-    player: Query<(Entity, &Transform, &LinearVelocity, &JumpCount), With<Player>>,
+    map: Res<Map>,
+    mut checkpoints: Query<(&Collider, &Transform, &mut Checkpoint)>,
+    mut players: Query<
+        (
+            Entity,
+            &Collider,
+            &Transform,
+            &LinearVelocity,
+            &JumpCount,
+            &TickInput,
+            Option<&InterpolatedPosition>,
+            &mut CheckpointProgress,
+            Option<(&MapDuration, &mut Splits)>,
+            Option<&GhostDelay>,
+        ),
+        (With<Player>, With<ResetSnapshot>),
+    >,
     camera: Query<&LeashedCamera>,
+    mut ew: EventWriter<WrongCheckpoint>,
 ) {
-    if let Ok((player, transform, vel, jc)) = player.get_single() {
-        for (colliding, mut checkpoint) in &mut query {
-            if colliding.contains(&player) {
+    for (
+        entity,
+        collider,
+        transform,
+        vel,
+        jc,
+        input,
+        interpolated,
+        mut progress,
+        mut splits,
+        ghost_delay,
+    ) in &mut players
+    {
+        for (checkpoint_collider, checkpoint_transform, mut checkpoint) in &mut checkpoints {
+            let inside = intersection_test(
+                collider,
+                transform.translation,
+                transform.rotation,
+                checkpoint_collider,
+                checkpoint_transform.translation,
+                checkpoint_transform.rotation,
+            )
+            .expect("Unsupported intersection shape!");
+            if !inside {
+                continue;
+            }
+
+            if map.ordered_checkpoints {
+                if checkpoint.index > progress.next {
+                    ew.send(WrongCheckpoint {
+                        expected: progress.next,
+                    });
+                    continue;
+                }
+                if checkpoint.index == progress.next {
+                    progress.next += 1;
+                }
+            }
+
+            // Headless runs have no camera, the yaw of the input is all there is.
+            let camera = camera
+                .get_single()
+                .map_or((input.yaw, 0.), |camera| (camera.yaw, camera.pitch));
+
+            if !checkpoint.reached {
                 checkpoint.reached = true;
-                let camera = camera.single();
-
-                commands.entity(player).insert(ResetSnapshot {
-                    pos: transform.translation,
-                    vel: vel.0,
-                    camera: (camera.yaw, camera.pitch),
-                    jump_count: jc.0,
-                });
+
+                if let Some((duration, splits)) = &mut splits {
+                    let fraction = crossing_fraction(
+                        collider,
+                        interpolated,
+                        transform.rotation,
+                        checkpoint_collider,
+                        checkpoint_transform,
+                    );
+                    splits.record(checkpoint.index, duration, fraction);
+                }
             }
+
+            let ghost_time = match (&splits, ghost_delay) {
+                (Some((duration, _)), Some(delay)) => duration.elapsed().saturating_sub(delay.0),
+                _ => Duration::ZERO,
+            };
+            commands.entity(entity).insert(ResetSnapshot {
+                pos: transform.translation,
+                vel: vel.0,
+                camera,
+                jump_count: jc.0,
+                ghost_time,
+            });
         }
     }
 }
 
-pub fn all_checkpoints_reached(
-    mut commands: Commands,
-    query: Query<&Checkpoint>,
-    player: Query<Entity, With<Player>>,
+/// Marks the checkpoint the player has to reach next on ordered maps.
+pub fn highlight_next_checkpoint(
+    mut gizmos: Gizmos,
+    map: Res<Map>,
+    player: Query<&CheckpointProgress, With<Player>>,
+    checkpoints: Query<(&Transform, &Checkpoint)>,
 ) {
-    if let Ok(player) = player.get_single() {
-        if query.iter().all(|checkpoint| checkpoint.reached) {
-            commands.entity(player).insert(AllCheckpointsReached);
+    if !map.ordered_checkpoints {
+        return;
+    }
+    let Ok(progress) = player.get_single() else {
+        return;
+    };
+
+    for (transform, checkpoint) in &checkpoints {
+        if checkpoint.index == progress.next {
+            gizmos
+                .circle(
+                    transform.translation,
+                    transform.rotation * Vec3::Z,
+                    8.,
+                    Color::GOLD,
+                )
+                .segments(48);
         }
     }
 }
 
+/// Tells the player which checkpoint to go to after touching one out of order.
+pub fn display_wrong_checkpoint(
+    mut contexts: EguiContexts,
+    mut er: EventReader<WrongCheckpoint>,
+    time: Res<Time>,
+    mut shown: Local<Option<(usize, f32)>>,
+) {
+    if let Some(e) = er.read().last() {
+        *shown = Some((e.expected, 2.));
+    }
+
+    let Some((expected, remaining)) = shown.as_mut() else {
+        return;
+    };
+    *remaining -= time.delta_seconds();
+    if *remaining <= 0. {
+        *shown = None;
+        return;
+    }
+
+    egui::Area::new("wrong checkpoint")
+        .anchor(Align2::CENTER_TOP, [0., 130.])
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(
+                RichText::new(format!("Reach checkpoint {} first!", *expected + 1))
+                    .size(24.)
+                    .color(Color32::DARK_RED),
+            );
+        });
+}
+
+/// Counts laps and finishes the run when the player enters the goal after all checkpoints.
+/// Runs on the simulation tick, so a replayed run completes its laps on the same tick the recorded one did.
 fn on_goal(
     mut commands: Commands,
-    oneshots: Res<GhostOneshots>,
+    // Both are missing when running headless.
+    oneshots: Option<Res<GhostOneshots>>,
     goals: Query<(&Collider, &Transform), With<Goal>>,
+    mut checkpoints: Query<&mut Checkpoint>,
     map: Res<Map>,
-    mut player: Query<
+    mut players: Query<
         (
             &Collider,
             &Transform,
-            Has<AllCheckpointsReached>,
+            Option<&InterpolatedPosition>,
+            &mut CheckpointProgress,
             Option<&mut MapDuration>,
+            Option<&mut Splits>,
+            Option<&mut ReplayRecorder>,
+            Has<ReplayPlayback>,
+            Has<Tuned>,
         ),
         With<Player>,
     >,
@@ -86,37 +338,101 @@ fn on_goal(
     mut windows: Query<&mut Window>,
     mut ew: EventWriter<LeaderboardEvent>,
 ) {
-    let (pcollider, ptransform, all_checkpoints_reached, mut mapduration) = player.single_mut();
+    for (
+        collider,
+        transform,
+        interpolated,
+        mut progress,
+        mapduration,
+        mut splits,
+        recorder,
+        is_replay,
+        is_tuned,
+    ) in &mut players
+    {
+        let goal = goals.iter().find(|(goal_collider, goal_transform)| {
+            intersection_test(
+                collider,
+                transform.translation,
+                transform.rotation,
+                goal_collider,
+                goal_transform.translation,
+                goal_transform.rotation,
+            )
+            .expect("Unsupported intersection shape!")
+        });
+        let inside = goal.is_some();
+        let entered = inside && !progress.in_goal;
+        progress.in_goal = inside;
+        if !entered {
+            continue;
+        }
 
-    for (collider, transform) in &goals {
-        match intersection_test(
-            pcollider,
-            ptransform.translation,
-            ptransform.rotation,
-            collider,
-            transform.translation,
-            transform.rotation,
-        ) {
-            Ok(b) => {
-                if b && all_checkpoints_reached {
-                    commands.run_system(oneshots.store);
-
-                    state.set(State::Finished);
-
-                    if let Some(ref mut mapduration) = mapduration {
-                        mapduration.stop();
-                        ew.send(LeaderboardEvent::SaveLeaderboardData(
-                            map.name.clone(),
-                            mapduration.elapsed().as_secs_f32(),
-                        ));
-                    }
-
-                    let mut window = windows.single_mut();
-                    window.cursor.grab_mode = CursorGrabMode::None;
-                    window.cursor.visible = true;
-                }
+        if !checkpoints.iter().all(|checkpoint| checkpoint.reached) {
+            continue;
+        }
+        progress.next = 0;
+
+        let Some(mut mapduration) = mapduration else {
+            continue;
+        };
+        let fraction = goal.map_or(1., |(goal_collider, goal_transform)| {
+            crossing_fraction(
+                collider,
+                interpolated,
+                transform.rotation,
+                goal_collider,
+                goal_transform,
+            )
+        });
+
+        if mapduration.laps_completed() + 1 < map.laps as usize {
+            if let Some(splits) = &mut splits {
+                // The start/finish line is the split after the last checkpoint.
+                splits.record(map.checkpoints.len(), &mapduration, fraction);
+            }
+            mapduration.complete_lap(fraction);
+            for mut checkpoint in &mut checkpoints {
+                checkpoint.reached = false;
             }
-            Err(_) => panic!("Unsupported intersection shape!"),
+            continue;
+        }
+
+        state.set(State::Finished);
+
+        mapduration.stop(fraction);
+        mapduration.complete_lap(fraction);
+        if let Some(splits) = &mut splits {
+            splits.finish(&mapduration);
+        }
+        if let Some(mut recorder) = recorder {
+            recorder.0.finish = Some(mapduration.elapsed());
+        }
+
+        // A watched replay is not a new run, a tuned one isn't comparable.
+        if !is_replay && !is_tuned {
+            if let Some(oneshots) = &oneshots {
+                commands.run_system(oneshots.store);
+            }
+
+            ew.send(LeaderboardEvent::SaveLeaderboardData(
+                map.name.clone(),
+                Highscore {
+                    time: mapduration.elapsed().as_secs_f32(),
+                    best_lap: (map.laps > 1).then(|| mapduration.best_lap().as_secs_f32()),
+                    splits: splits
+                        .map(|splits| splits.0.splits.clone())
+                        .unwrap_or_default(),
+                    profile: map.profile.clone(),
+                    controller_hash: map.movement.hash(),
+                    ..Default::default()
+                },
+            ));
+        }
+
+        if let Ok(mut window) = windows.get_single_mut() {
+            window.cursor.grab_mode = CursorGrabMode::None;
+            window.cursor.visible = true;
         }
     }
 }
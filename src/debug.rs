@@ -1,13 +1,14 @@
 use bevy::prelude::*;
-use bevy_xpbd_3d::prelude::{Collider, CollisionLayers, RigidBody, Sensor};
 
 use crate::{
-    assets::AssetHandles, character_controller::MaxSlopeAngle, checkpoint::Checkpoint, map::Map,
-    physics::PhysicsLayers, MapEntityMarker, Player,
+    assets::AssetHandles,
+    checkpoint::{spawn_checkpoints, Checkpoint},
+    map::Map,
+    Player,
 };
 
 pub fn debug_things(
-    mut player: Query<(&Transform, &mut MaxSlopeAngle), With<Player>>,
+    player: Query<&Transform, With<Player>>,
     keyboard_input: Res<Input<KeyCode>>,
     aserv: Res<AssetServer>,
     old_map: Res<Map>,
@@ -16,7 +17,7 @@ pub fn debug_things(
     query: Query<Entity, With<Checkpoint>>,
 ) {
     if keyboard_input.just_pressed(KeyCode::L) {
-        if let Ok((player, _)) = player.get_single() {
+        if let Ok(player) = player.get_single() {
             dbg!(player);
             println!(
                 "[{},{},{}]",
@@ -25,37 +26,22 @@ pub fn debug_things(
         }
     }
 
-    if keyboard_input.just_pressed(KeyCode::P) {
-        if let Ok((_, mut max)) = player.get_single_mut() {
-            max.0 = 180.;
-        }
-    }
-
     if keyboard_input.just_pressed(KeyCode::R) {
+        let map = match Map::load(&old_map.name) {
+            Ok(map) => map,
+            Err(e) => {
+                println!("Could not reload map {}: {e}", old_map.name);
+                return;
+            }
+        };
+
         aserv.reload(format!("{}.glb#Scene0", old_map.file));
 
         for e in &query {
             commands.entity(e).despawn_recursive();
         }
 
-        let map = Map::load(&old_map.name);
-        for checkpoint in &map.checkpoints {
-            commands.spawn((
-                SceneBundle {
-                    scene: asset_handles.tori.clone_weak(),
-                    transform: Transform::from_translation(checkpoint.pos)
-                        .with_scale(Vec3::splat(2.))
-                        .with_rotation(Quat::from_rotation_y(checkpoint.rot.to_radians())),
-                    ..Default::default()
-                },
-                Sensor,
-                CollisionLayers::new([PhysicsLayers::Sensor], [PhysicsLayers::Sensor]),
-                Collider::cuboid(10., 20., 3.),
-                RigidBody::Static,
-                Checkpoint { reached: false },
-                MapEntityMarker,
-            ));
-        }
+        spawn_checkpoints(&mut commands, &map, &asset_handles.tori);
         commands.insert_resource(map);
         // commands.spawn((
         //     Name::new("Map"),
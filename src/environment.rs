@@ -1,5 +1,5 @@
 use bevy::{
-    pbr::{CascadeShadowConfigBuilder, DirectionalLightShadowMap, NotShadowCaster},
+    pbr::{CascadeShadowConfigBuilder, NotShadowCaster},
     prelude::*,
 };
 
@@ -15,8 +15,6 @@ pub fn spawn_sky(
     }
     .build();
 
-    commands.insert_resource(DirectionalLightShadowMap { size: 2048 });
-
     // Sun
     commands.spawn(DirectionalLightBundle {
         directional_light: DirectionalLight {
@@ -1,25 +1,53 @@
 use std::{
-    fs::File,
-    io::{Read, Write},
-    path::Path,
-    time::Duration,
+    collections::HashMap,
+    path::{Path, PathBuf},
 };
 
-use bevy::{ecs::system::SystemId, prelude::*};
-use bevy_tweening::{
-    lens::{TransformPositionLens, TransformRotationLens},
-    Animator, EaseMethod, Lens, Sequence, Tween, TweeningPlugin,
+use bevy::{
+    ecs::system::SystemId,
+    prelude::*,
+    window::{CursorGrabMode, FileDragAndDrop},
 };
+use bevy_egui::{
+    egui::{self, Align2, Color32, Frame, Margin, RichText},
+    EguiContexts,
+};
+use instant::Duration;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    assets::{Animations, AssetHandles},
-    character_controller::CharacterController,
+    assets::AssetHandles,
+    character_controller::{AnimationState, ControllerSet, MovementAction, TickInput},
+    input::ResetSnapshot,
+    leaderboard::{replay_file, MapHighscores},
     map::Map,
-    timing::Countdown,
-    MapEntityMarker,
+    physics::InterpolatedModel,
+    player::start_transform,
+    replay::{
+        follow_tracks, imported_replay_path, last_replay_path, legacy_replay_path, replay_path,
+        Replay, ReplayRecorder, TrackPlayback,
+    },
+    storage::{self, StorageError, StorageErrors},
+    timing::{advance_clock, format_seconds, MapDuration},
+    ui::show_storage_errors,
+    MapEntityMarker, Player, StateOneshots,
 };
 
+const GHOSTS_FILE: &str = "ghosts.json";
+
+/// Colours of the selected ghosts, in the order they were picked.
+const GHOST_COLORS: [Color; 6] = [
+    Color::CYAN,
+    Color::ORANGE,
+    Color::FUCHSIA,
+    Color::LIME_GREEN,
+    Color::GOLD,
+    Color::TOMATO,
+];
+
+/// Height of the name label above a ghost.
+const LABEL_OFFSET: Vec3 = Vec3::new(0., 1.5, 0.);
+
 pub struct GhostPlugin;
 
 #[derive(Resource)]
@@ -30,31 +58,120 @@ pub struct GhostOneshots {
 
 impl Plugin for GhostPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(TweeningPlugin)
-            .add_systems(Startup, register_oneshots)
+        app.add_systems(Startup, (register_oneshots, load_ghost_selections))
             .add_systems(
                 Update,
-                (make_ghost_scene_transparent, ghost_recorder, animate_ghost)
+                (tint_ghost_materials, display_ghost_labels).run_if(
+                    in_state(crate::State::Playing).or_else(in_state(crate::State::Replay)),
+                ),
+            )
+            .add_systems(
+                FixedUpdate,
+                rewind_ghosts
+                    .after(advance_clock)
+                    .before(ControllerSet::Movement)
                     .run_if(in_state(crate::State::Playing)),
+            )
+            .add_systems(
+                Update,
+                sync_ghosts_to_clock
+                    .before(follow_tracks)
+                    .run_if(in_state(crate::State::Playing)),
+            )
+            .add_systems(
+                Update,
+                (ui_ghost_select, import_dropped_replays)
+                    .run_if(in_state(crate::State::GhostSelect)),
             );
     }
 }
 
-#[derive(Component, Serialize, Deserialize, Default)]
-pub struct GhostData {
-    log: Vec<(Vec3, Quat)>,
-    duration: Vec<f32>,
-}
-
+/// A recorded run shown next to the player's, following its track in step with the run's clock.
 #[derive(Component)]
-pub struct GhostDataIndex(usize);
+pub struct Ghost {
+    pub name: String,
+    pub color: Color,
+}
 
-#[derive(Component)]
-pub struct Ghost;
+/// How far the ghosts are behind the run's clock, on the [`Player`].
+/// Resetting to a checkpoint sends them back to where they were when the player passed it.
+#[derive(Component, Default)]
+pub struct GhostDelay(pub Duration);
 
 #[derive(Resource)]
 pub struct MapName(pub String);
 
+/// Where the replay of a ghost comes from.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum GhostSource {
+    /// The fastest local run.
+    PersonalBest,
+    /// The most recent finished run.
+    LastAttempt,
+    /// A leaderboard entry, by the id of its replay.
+    Leaderboard { id: String, label: String },
+    /// A replay imported from a file, by its name.
+    Imported(String),
+}
+
+impl GhostSource {
+    fn path(&self, map: &str) -> PathBuf {
+        match self {
+            GhostSource::PersonalBest => replay_path(map),
+            GhostSource::LastAttempt => last_replay_path(map),
+            GhostSource::Leaderboard { id, .. } => replay_file(id),
+            GhostSource::Imported(name) => imported_replay_path(map, name),
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            GhostSource::PersonalBest => "Personal best",
+            GhostSource::LastAttempt => "Last attempt",
+            GhostSource::Leaderboard { label, .. } => label,
+            GhostSource::Imported(name) => name,
+        }
+    }
+}
+
+/// The ghosts to race against on one map.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MapGhosts {
+    pub selected: Vec<GhostSource>,
+    /// Names of the replays imported for this map.
+    pub imported: Vec<String>,
+}
+
+impl Default for MapGhosts {
+    fn default() -> Self {
+        Self {
+            selected: vec![GhostSource::PersonalBest],
+            imported: vec![],
+        }
+    }
+}
+
+#[derive(Default, Resource, Serialize, Deserialize)]
+pub struct GhostSelections {
+    maps: HashMap<String, MapGhosts>,
+}
+
+impl GhostSelections {
+    pub fn get(&self, map: &str) -> MapGhosts {
+        self.maps.get(map).cloned().unwrap_or_default()
+    }
+}
+
+pub fn ghost_color(index: usize) -> Color {
+    GHOST_COLORS[index % GHOST_COLORS.len()]
+}
+
+pub fn to_color32(color: Color) -> Color32 {
+    let [r, g, b, _] = color.as_rgba_u8();
+    Color32::from_rgb(r, g, b)
+}
+
 pub fn register_oneshots(world: &mut World) {
     let load = world.register_system(replay_ghost);
     let store = world.register_system(store_ghost);
@@ -62,156 +179,379 @@ pub fn register_oneshots(world: &mut World) {
     world.insert_resource(GhostOneshots { load, store });
 }
 
+fn load_ghost_selections(mut commands: Commands, mut errors: ResMut<StorageErrors>) {
+    #[cfg(target_arch = "wasm32")]
+    {
+        commands.init_resource::<GhostSelections>();
+        return;
+    }
+
+    let selections: GhostSelections = storage::load_json_or_default(GHOSTS_FILE, &mut errors);
+    commands.insert_resource(selections);
+}
+
+/// Saves the run as the last attempt, and as the personal best if it is faster.
 pub fn store_ghost(
     map: Res<Map>,
-    data: Query<&GhostData, Without<Ghost>>,
-    old_data: Query<&GhostData, With<Ghost>>,
+    recorder: Query<&ReplayRecorder>,
+    mut errors: ResMut<StorageErrors>,
 ) {
-    let data = data.single();
-    let serialized = serde_json::to_string(data).unwrap();
+    #[cfg(target_arch = "wasm32")]
+    return;
 
-    if let Ok(old_data) = old_data.get_single() {
+    let replay = &recorder.single().0;
+    errors.report(replay.save(&last_replay_path(&map.name)));
+
+    let old_data = errors
+        .report(Replay::load(&replay_path(&map.name)))
+        .flatten()
+        .filter(|old_data| old_data.is_playable_on(&map));
+    if let Some(old_data) = old_data {
         println!(
             "Old ghost data: {}, new: {}",
-            old_data.duration.iter().sum::<f32>(),
-            data.duration.iter().sum::<f32>()
+            old_data.time().as_secs_f32(),
+            replay.time().as_secs_f32()
         );
-        if old_data.duration.iter().sum::<f32>() < data.duration.iter().sum() {
+        if old_data.time() < replay.time() {
             return;
         }
     }
 
     println!("New ghost is faster, overwriting old ghost.");
-    // Create or truncate file
 
-    #[cfg(not(target_arch = "wasm32"))]
-    {
-        let mut file = File::create(format!("maps/{}.replay", &map.name)).unwrap();
-        _ = file.write_all(serialized.as_bytes());
-    }
+    errors.report(replay.save(&replay_path(&map.name)));
 }
 
-pub fn ghost_recorder(
-    mut player: Query<(&mut GhostData, &Transform), With<CharacterController>>,
-    time: Res<Time>,
-    mut elapsed: Local<f32>,
+/// Spawns a ghost for every replay selected for the map.
+pub fn replay_ghost(
+    map: Res<Map>,
+    handles: Res<AssetHandles>,
+    selections: Res<GhostSelections>,
+    mut commands: Commands,
+    mut errors: ResMut<StorageErrors>,
 ) {
-    let (mut ghost_data, transform) = player.single_mut();
-
-    let dt = time.delta_seconds();
-
-    *elapsed += dt;
+    #[cfg(target_arch = "wasm32")]
+    return;
+
+    errors.report(storage::migrate_legacy(
+        &legacy_replay_path(&map.name),
+        replay_path(&map.name),
+    ));
+
+    for (index, source) in selections.get(&map.name).selected.iter().enumerate() {
+        // Leaderboard entries can be dropped, their replays are deleted with them.
+        let Some(replay) = errors
+            .report(Replay::load(&source.path(&map.name)))
+            .flatten()
+        else {
+            continue;
+        };
+
+        // Ghosts only follow the recorded track, they don't need to re-simulate.
+        if !replay.is_watchable_on(&map) {
+            println!(
+                "Ghost {} is from an older replay format or another map, skipping it.",
+                source.label()
+            );
+            continue;
+        }
 
-    if *elapsed > 0.3 {
-        ghost_data
-            .log
-            .push((transform.translation, transform.rotation));
-        ghost_data.duration.push(*elapsed);
-        *elapsed = 0.;
+        spawn_ghost(
+            &mut commands,
+            &map,
+            &handles,
+            replay,
+            Ghost {
+                name: source.label().to_string(),
+                color: ghost_color(index),
+            },
+        );
     }
 }
 
-pub struct RotationTranslationLens {
-    pub start: Vec3,
-    pub end: Vec3,
-    pub rstart: Quat,
-    pub rend: Quat,
+/// Spawns a ghost at the start of the map, its [`TrackPlayback`] is still at the start.
+pub fn spawn_ghost(
+    commands: &mut Commands,
+    map: &Map,
+    handles: &AssetHandles,
+    replay: Replay,
+    ghost: Ghost,
+) -> Entity {
+    let transform = start_transform(map);
+
+    commands
+        .spawn((
+            Name::new(format!("Ghost {}", ghost.name)),
+            SpatialBundle::from_transform(transform),
+            TrackPlayback::new(replay),
+            AnimationState::default(),
+            ghost,
+            MapEntityMarker,
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                SceneBundle {
+                    scene: handles.fox.clone(),
+                    ..Default::default()
+                },
+                InterpolatedModel,
+            ));
+        })
+        .id()
 }
 
-impl Lens<Transform> for RotationTranslationLens {
-    fn lerp(&mut self, target: &mut Transform, ratio: f32) {
-        let value = self.start + (self.end - self.start) * ratio;
-        target.translation = value;
-        target.rotation = self.rstart.slerp(self.rend, ratio);
+/// Copies a replay file into the data directory and selects it as a ghost on the map.
+fn import_replay(path: &Path, map: &Map, ghosts: &mut MapGhosts) -> Result<(), StorageError> {
+    let replay = storage::import(path, |bytes| {
+        Replay::decode(bytes).map_err(|e| e.to_string())
+    })?;
+    if !replay.is_playable_on(map) {
+        return Err(StorageError::Invalid {
+            path: path.to_owned(),
+            message: format!(
+                "not a replay of {} with the {} profile from this version of the game",
+                map.name, map.profile
+            ),
+        });
     }
-}
 
-pub fn replay_ghost(map: Res<Map>, handles: Res<AssetHandles>, mut commands: Commands) {
-    let name = format!("maps/{}.replay", &map.name);
-    let path = Path::new(&name);
-    if !path.exists() {
-        return;
+    let stem = path.file_stem().map_or("imported".into(), |stem| {
+        stem.to_string_lossy().into_owned()
+    });
+    let mut name = stem.clone();
+    let mut n = 2;
+    while ghosts.imported.contains(&name) {
+        name = format!("{stem} ({n})");
+        n += 1;
     }
 
-    let mut file = File::open(path).unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-
-    let data = serde_json::from_str::<GhostData>(&contents);
-
-    if let Ok(data) = data {
-        let tweens = (0..(data.log.len() - 1)).map(|i| {
-            Tween::new(
-                EaseMethod::Linear,
-                Duration::from_secs_f32(data.duration[i]),
-                RotationTranslationLens {
-                    start: data.log[i].0,
-                    end: data.log[i + 1].0,
-                    rstart: data.log[i].1,
-                    rend: data.log[i].1,
-                },
-            )
-        });
-
-        let sequence = Sequence::new(tweens);
+    replay.save(&imported_replay_path(&map.name, &name))?;
+    println!("Imported {} as ghost {name}", path.display());
+    ghosts.imported.push(name.clone());
+    ghosts.selected.push(GhostSource::Imported(name));
+    Ok(())
+}
 
-        commands.spawn((
-            Name::new("Ghost"),
-            SceneBundle {
-                scene: handles.fox.clone(),
-                ..Default::default()
-            },
-            Animator::new(sequence),
-            Ghost,
-            data, // Inserted in order to compare the old with the new time
-            MapEntityMarker,
-        ));
+fn import_dropped_replays(
+    mut er: EventReader<FileDragAndDrop>,
+    map: Res<Map>,
+    mut selections: ResMut<GhostSelections>,
+    mut errors: ResMut<StorageErrors>,
+) {
+    for e in er.read() {
+        if let FileDragAndDrop::DroppedFile { path_buf, .. } = e {
+            let ghosts = selections.maps.entry(map.name.clone()).or_default();
+            errors.report(import_replay(path_buf, &map, ghosts));
+        }
     }
 }
 
-fn make_ghost_scene_transparent(
+/// Lets the player pick the ghosts to race against before a run on the [`Map`].
+pub fn ui_ghost_select(
     mut commands: Commands,
-    query: Query<Entity, Added<Ghost>>,
-    children: Query<&Children>,
-    materials: Query<&Handle<StandardMaterial>>,
-    mut assets: ResMut<Assets<StandardMaterial>>,
+    mut contexts: EguiContexts,
+    mut state: ResMut<NextState<crate::State>>,
+    mut windows: Query<&mut Window>,
+    map: Res<Map>,
+    highscores: Res<MapHighscores>,
+    mut selections: ResMut<GhostSelections>,
+    oneshots: Res<StateOneshots>,
+    ghost_oneshots: Res<GhostOneshots>,
+    mut errors: ResMut<StorageErrors>,
+    mut import_path: Local<String>,
 ) {
-    for e in &query {
-        for child in children.iter_descendants(e) {
-            if let Ok(material) = materials.get(child) {
-                // Clone and overwrite the ghosts material.
-                // If not cloned and overwritten, the players also changes.
-                let mut material = assets.get(material).expect("Must have material.").clone();
-
-                material.alpha_mode = AlphaMode::Blend;
-                material.base_color.set_a(0.5);
+    let ghosts = selections.maps.entry(map.name.clone()).or_default();
+
+    let mut available: Vec<GhostSource> = [GhostSource::PersonalBest, GhostSource::LastAttempt]
+        .into_iter()
+        .filter(|source| storage::path(source.path(&map.name)).is_ok_and(|path| path.exists()))
+        .collect();
+    available.extend(highscores.current_entries(&map).filter_map(|h| {
+        let name = if h.name.is_empty() {
+            "-"
+        } else {
+            h.name.as_str()
+        };
+        Some(GhostSource::Leaderboard {
+            id: h.replay.clone()?,
+            label: format!("{name} {}", format_seconds(h.time)),
+        })
+    }));
+    available.extend(ghosts.imported.iter().cloned().map(GhostSource::Imported));
+
+    let mut import = false;
+    let mut back = false;
+    let mut start = false;
+
+    let ctx = contexts.ctx_mut();
+    egui::Area::new("ghosts").show(ctx, |ui| {
+        Frame {
+            outer_margin: Margin::symmetric(300., 0.),
+            inner_margin: Margin::same(20.),
+            fill: Color32::from_rgba_unmultiplied(255, 255, 255, 150),
+            ..Default::default()
+        }
+        .show(ui, |ui| {
+            ui.vertical_centered(|ui| {
+                ui.heading(format!("Ghosts on {}", map.name));
+
+                if available.is_empty() {
+                    ui.label("No ghosts yet, finish a run or import a replay.");
+                }
+                egui::ScrollArea::vertical()
+                    .max_height(300.)
+                    .show(ui, |ui| {
+                        for source in available {
+                            let index = ghosts.selected.iter().position(|s| *s == source);
+                            let mut label = RichText::new(source.label());
+                            if let Some(index) = index {
+                                label = label.color(to_color32(ghost_color(index))).strong();
+                            }
+
+                            let mut checked = index.is_some();
+                            if ui.checkbox(&mut checked, label).changed() {
+                                match index {
+                                    Some(index) => {
+                                        ghosts.selected.remove(index);
+                                    }
+                                    None => ghosts.selected.push(source),
+                                }
+                            }
+                        }
+                    });
+
+                ui.horizontal(|ui| {
+                    ui.label("Import:");
+                    ui.text_edit_singleline(&mut *import_path);
+                    import = ui.button("Import").clicked();
+                });
+                ui.label("Replay files can also be dropped onto the window.");
+
+                ui.horizontal(|ui| {
+                    back = ui.button("Back").clicked();
+                    start = ui.button("Start").clicked();
+                });
+            });
+            show_storage_errors(ui, &mut errors);
+        })
+    });
+
+    if import
+        && errors
+            .report(import_replay(Path::new(import_path.trim()), &map, ghosts))
+            .is_some()
+    {
+        import_path.clear();
+    }
 
-                let handle = assets.add(material);
+    if back || start {
+        #[cfg(not(target_arch = "wasm32"))]
+        errors.report(storage::save_json(GHOSTS_FILE, &*selections));
+    }
 
-                commands.entity(child).insert(handle);
-            }
-        }
+    if back {
+        state.set(crate::State::Mainscreen);
+    } else if start {
+        commands.run_system(oneshots.unload);
+        let mut window = windows.single_mut();
+        window.cursor.grab_mode = CursorGrabMode::Locked;
+        window.cursor.visible = false;
+        commands.run_system(oneshots.load_map);
+        commands.run_system(ghost_oneshots.load);
+        state.set(crate::State::Playing);
     }
 }
 
-fn animate_ghost(
-    mut animation_player: Query<&mut AnimationPlayer>,
-    query: Query<Entity, With<Ghost>>,
-    countdown: Query<&Countdown>,
-    animations: Res<Animations>,
-    children: Query<&Children>,
+/// Shows the name of each ghost above its model, in the ghost's colour.
+fn display_ghost_labels(
+    mut contexts: EguiContexts,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    ghosts: Query<(Entity, &Ghost, &GlobalTransform)>,
 ) {
-    if countdown.get_single().is_ok() {
+    let Some((camera, camera_transform)) = cameras.iter().find(|(camera, _)| camera.is_active)
+    else {
         return;
+    };
+
+    let ctx = contexts.ctx_mut();
+    for (entity, ghost, transform) in &ghosts {
+        let Some(pos) =
+            camera.world_to_viewport(camera_transform, transform.translation() + LABEL_OFFSET)
+        else {
+            continue;
+        };
+
+        egui::Area::new(egui::Id::new(("ghost label", entity)))
+            .fixed_pos([pos.x, pos.y])
+            .pivot(Align2::CENTER_BOTTOM)
+            .interactable(false)
+            .show(ctx, |ui| {
+                ui.label(
+                    RichText::new(&ghost.name)
+                        .color(to_color32(ghost.color))
+                        .strong(),
+                );
+            });
     }
+}
 
-    for e in &query {
-        for entity in children.iter_descendants(e) {
-            if let Ok(mut animation_player) = animation_player.get_mut(entity) {
-                animation_player
-                    .play_with_transition(animations.0[1].clone_weak(), Duration::from_millis(100))
-                    .repeat();
-            }
+/// Applies [`MovementAction::Reset`] to the [`GhostDelay`], on the same tick the player is reset.
+pub fn rewind_ghosts(
+    mut player: Query<(&TickInput, &ResetSnapshot, &MapDuration, &mut GhostDelay), With<Player>>,
+) {
+    for (input, snapshot, duration, mut delay) in &mut player {
+        if input.actions.contains(&MovementAction::Reset) {
+            delay.0 = duration.elapsed().saturating_sub(snapshot.ghost_time);
         }
     }
 }
+
+/// Ghosts start when the countdown ends, stop while the game is paused,
+/// go back with the player on resets and start over when the run is restarted.
+pub fn sync_ghosts_to_clock(
+    time: Res<Time<Fixed>>,
+    player: Query<(&MapDuration, &GhostDelay), With<Player>>,
+    mut ghosts: Query<&mut TrackPlayback, With<Ghost>>,
+) {
+    // Interpolated like the player's model, so both are shown at the same point in time.
+    let now = player
+        .get_single()
+        .map_or(Duration::ZERO, |(duration, delay)| {
+            duration
+                .during_tick(time.overstep_percentage_f64())
+                .saturating_sub(delay.0)
+        });
+    for mut ghost in &mut ghosts {
+        ghost.time = now;
+    }
+}
+
+/// Tints the materials of a ghost's model with its colour and makes them translucent.
+fn tint_ghost_materials(
+    mut commands: Commands,
+    query: Query<(Entity, &Handle<StandardMaterial>), Added<Handle<StandardMaterial>>>,
+    parents: Query<&Parent>,
+    ghosts: Query<&Ghost>,
+    mut assets: ResMut<Assets<StandardMaterial>>,
+) {
+    // The model is a scene, its meshes are only spawned some frames after the ghost.
+    for (entity, material) in &query {
+        let Some(ghost) = parents
+            .iter_ancestors(entity)
+            .find_map(|ancestor| ghosts.get(ancestor).ok())
+        else {
+            continue;
+        };
+
+        // Clone and overwrite the ghosts material.
+        // If not cloned and overwritten, the players also changes.
+        let mut material = assets.get(material).expect("Must have material.").clone();
+
+        material.alpha_mode = AlphaMode::Blend;
+        material.base_color = ghost.color.with_a(0.5);
+
+        let handle = assets.add(material);
+
+        commands.entity(entity).insert(handle);
+    }
+}
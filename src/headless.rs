@@ -0,0 +1,415 @@
+//! Runs the gameplay without a window or renderer, so maps and the character controller
+//! can be tested on machines without a GPU.
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    app::PluginsState,
+    asset::{LoadState, RecursiveDependencyLoadState},
+    ecs::system::RunSystemOnce,
+    gltf::{GltfExtras, GltfPlugin},
+    input::InputPlugin,
+    prelude::*,
+    render::mesh::skinning::SkinnedMeshInverseBindposes,
+    scene::ScenePlugin,
+    time::TimeUpdateStrategy,
+};
+use bevy_xpbd_3d::prelude::{Collider, CollisionLayers, LinearVelocity, Position, RigidBody};
+
+use crate::{
+    character_controller::{AnimationState, ControllerSet},
+    checkpoint::{
+        self, checkpoint_bundle, checkpoint_transform, goal_collider, goal_transform, Goal,
+    },
+    ghost::{rewind_ghosts, sync_ghosts_to_clock, Ghost},
+    jumppad::jumppad_bundle,
+    map::{scene_path, MAP_OFFSET},
+    physics::{PhysicsLayers, TICK_RATE},
+    player::player_bundle,
+    replay::{follow_tracks, ReplayPlayback, ReplayRecorder, TrackPlayback},
+    restart,
+    splits::Splits,
+    timing::{advance_clock, MapDuration},
+    tuning::{apply_tuning, reset_on_map_change, MovementTuning, Tuned},
+    GameplayPlugin, MapEntityMarker, State,
+};
+pub use crate::{
+    character_controller::{MovementAction, TickInput},
+    map::{Checkpoint, Map, MapError},
+    profile::MovementProfile,
+    replay::Replay,
+    replay_format::DecodeError,
+    surface::Surface,
+};
+
+/// Name of the colliders spawned for the map's meshes.
+const MAP_COLLIDER: &str = "Map collider";
+
+/// How long to wait for the map's glTF file before giving up.
+const LOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A run on a map, simulated one tick at a time.
+///
+/// The run starts right away, there is no countdown. The player is driven by
+/// the [`TickInput`]s passed to [`HeadlessGame::step`] instead of the keyboard.
+pub struct HeadlessGame {
+    app: App,
+    player: Entity,
+    start_yaw: f32,
+}
+
+impl HeadlessGame {
+    /// Parses a map from its JSON and sets up a run on it.
+    pub fn new(map_json: &str) -> Result<Self, MapError> {
+        Self::with_map(Map::parse(map_json)?)
+    }
+
+    pub fn with_map(map: Map) -> Result<Self, MapError> {
+        let mut app = App::new();
+
+        app.add_plugins((
+            MinimalPlugins,
+            AssetPlugin::default(),
+            TransformPlugin,
+            HierarchyPlugin,
+            InputPlugin,
+            ScenePlugin,
+            GltfPlugin::default(),
+            GameplayPlugin,
+        ))
+        // Normally registered by the render and pbr plugins, the glTF loader adds them to the scene.
+        .init_asset::<Mesh>()
+        .init_asset::<StandardMaterial>()
+        .init_asset::<Image>()
+        .init_asset::<AnimationClip>()
+        .init_asset::<SkinnedMeshInverseBindposes>()
+        // The tuning panel without its UI, values are set with `HeadlessGame::tune`.
+        .init_resource::<MovementTuning>()
+        .add_systems(
+            Update,
+            (reset_on_map_change, apply_tuning)
+                .chain()
+                .run_if(resource_exists::<Map>()),
+        )
+        // Ghosts without their models and labels.
+        .add_systems(
+            FixedUpdate,
+            rewind_ghosts
+                .after(advance_clock)
+                .before(ControllerSet::Movement)
+                .run_if(in_state(State::Playing)),
+        )
+        .add_systems(
+            Update,
+            sync_ghosts_to_clock
+                .before(follow_tracks)
+                .run_if(in_state(State::Playing)),
+        )
+        // Every update advances exactly one simulation tick.
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            1. / TICK_RATE,
+        )));
+
+        while app.plugins_state() == PluginsState::Adding {
+            bevy::tasks::tick_global_task_pools_on_main_thread();
+        }
+        app.finish();
+        app.cleanup();
+
+        spawn_map_colliders(&mut app, &map)?;
+
+        let start_yaw = map.start_rotation.to_radians();
+        app.world.insert_resource(map);
+        let player = app.world.run_system_once(spawn_run);
+        app.world
+            .resource_mut::<NextState<State>>()
+            .set(State::Playing);
+        // The state changes with the first step, so its input is the one of the first tick.
+
+        Ok(Self {
+            app,
+            player,
+            start_yaw,
+        })
+    }
+
+    /// Simulates one tick with the given input.
+    pub fn step(&mut self, input: &TickInput) {
+        self.app
+            .world
+            .get_mut::<ReplayPlayback>(self.player)
+            .expect("The player is never despawned.")
+            .play_next(input.clone());
+        self.app.update();
+    }
+
+    /// Simulates the same input for the given time, rounded to whole ticks.
+    pub fn hold(&mut self, input: &TickInput, seconds: f64) {
+        for _ in 0..(seconds * TICK_RATE).round() as usize {
+            self.step(input);
+        }
+    }
+
+    /// Simulates each input in order.
+    pub fn run_script<'a>(&mut self, inputs: impl IntoIterator<Item = &'a TickInput>) {
+        for input in inputs {
+            self.step(input);
+        }
+    }
+
+    /// Drives the player with other movement values, like the tuning panel does.
+    /// They apply from the tick after the next step on.
+    pub fn tune(&mut self, profile: MovementProfile) {
+        self.app.world.resource_mut::<MovementTuning>().profile = profile;
+    }
+
+    /// Whether the player is driven with tuned values, its run doesn't count then.
+    pub fn is_tuned(&self) -> bool {
+        self.app.world.get::<Tuned>(self.player).is_some()
+    }
+
+    /// Starts the run over like the restart action, with the clock running right away.
+    pub fn restart(&mut self) {
+        self.app.world.run_system_once(restart);
+        let map = self.app.world.resource::<Map>();
+        let playback = ReplayPlayback::new(Replay::new(map));
+        self.app.world.entity_mut(self.player).insert((
+            MapDuration::new(),
+            Splits::default(),
+            playback,
+        ));
+    }
+
+    /// Makes all of the map's geometry the given surface, whatever its glTF file says.
+    pub fn cover_ground(&mut self, surface: Surface) {
+        let world = &mut self.app.world;
+        let colliders: Vec<Entity> = world
+            .query::<(Entity, &Name)>()
+            .iter(world)
+            .filter(|(_, name)| name.as_str() == MAP_COLLIDER)
+            .map(|(entity, _)| entity)
+            .collect();
+        for entity in colliders {
+            world.entity_mut(entity).insert(surface);
+        }
+    }
+
+    /// Adds a ghost following the track of a replay in step with the run's clock, without a model.
+    pub fn add_ghost(&mut self, replay: Replay) -> Entity {
+        self.app
+            .world
+            .spawn((
+                TransformBundle::default(),
+                TrackPlayback::new(replay),
+                AnimationState::default(),
+                Ghost {
+                    name: "ghost".into(),
+                    color: Color::WHITE,
+                },
+                MapEntityMarker,
+            ))
+            .id()
+    }
+
+    /// Where a ghost added with [`HeadlessGame::add_ghost`] is on its track.
+    pub fn ghost_time(&self, ghost: Entity) -> Duration {
+        self.app
+            .world
+            .get::<TrackPlayback>(ghost)
+            .expect("Ghosts are never despawned.")
+            .time
+    }
+
+    /// Camera yaw at the start of the map.
+    /// [`MovementAction::Move`] relative to it moves in the direction the map starts facing.
+    pub fn start_yaw(&self) -> f32 {
+        self.start_yaw
+    }
+
+    pub fn player_position(&self) -> Vec3 {
+        self.app
+            .world
+            .get::<Position>(self.player)
+            .expect("The player is never despawned.")
+            .0
+    }
+
+    pub fn player_velocity(&self) -> Vec3 {
+        self.app
+            .world
+            .get::<LinearVelocity>(self.player)
+            .expect("The player is never despawned.")
+            .0
+    }
+
+    pub fn checkpoints_reached(&mut self) -> usize {
+        self.app
+            .world
+            .query::<&checkpoint::Checkpoint>()
+            .iter(&self.app.world)
+            .filter(|checkpoint| checkpoint.reached)
+            .count()
+    }
+
+    /// Laps finished so far, the last one included once the run is finished.
+    pub fn laps_completed(&self) -> usize {
+        self.app
+            .world
+            .get::<MapDuration>(self.player)
+            .expect("The player is never despawned.")
+            .laps_completed()
+    }
+
+    /// Whether the player reached the goal after all checkpoints.
+    pub fn is_finished(&self) -> bool {
+        *self
+            .app
+            .world
+            .resource::<bevy::prelude::State<State>>()
+            .get()
+            == State::Finished
+    }
+
+    /// Time on the run's clock, counted in simulated ticks.
+    pub fn run_time(&self) -> Duration {
+        self.app
+            .world
+            .get::<MapDuration>(self.player)
+            .expect("The player is never despawned.")
+            .elapsed()
+    }
+
+    /// The input of every tick simulated so far.
+    pub fn replay(&self) -> &Replay {
+        &self
+            .app
+            .world
+            .get::<ReplayRecorder>(self.player)
+            .expect("The player is never despawned.")
+            .0
+    }
+
+    /// The underlying app, for inspecting anything not covered above.
+    pub fn app(&mut self) -> &mut App {
+        &mut self.app
+    }
+}
+
+/// Loads the map's scene and spawns a static trimesh collider for every mesh in it,
+/// like the `AsyncSceneCollider` of the windowed game does.
+fn spawn_map_colliders(app: &mut App, map: &Map) -> Result<(), MapError> {
+    let path = scene_path(map);
+    let handle: Handle<Scene> = app.world.resource::<AssetServer>().load(path.clone());
+
+    let started = Instant::now();
+    loop {
+        app.update();
+
+        let server = app.world.resource::<AssetServer>();
+        if server.is_loaded_with_dependencies(&handle) {
+            break;
+        }
+        if server.load_state(&handle) == LoadState::Failed
+            || server.get_recursive_dependency_load_state(&handle)
+                == Some(RecursiveDependencyLoadState::Failed)
+            || started.elapsed() > LOAD_TIMEOUT
+        {
+            return Err(MapError::MissingScene(path));
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let scenes = app.world.resource::<Assets<Scene>>();
+    let meshes = app.world.resource::<Assets<Mesh>>();
+    let scene = &scenes.get(&handle).expect("Scene was just loaded.").world;
+
+    let mut colliders = vec![];
+    for entity in scene.iter_entities() {
+        let Some(mesh) = entity.get::<Handle<Mesh>>().and_then(|h| meshes.get(h)) else {
+            continue;
+        };
+        let Some(collider) = Collider::trimesh_from_mesh(mesh) else {
+            continue;
+        };
+
+        // Scene entities only have local transforms, walk up the hierarchy.
+        let mut transform = entity.get::<Transform>().copied().unwrap_or_default();
+        let mut parent = entity.get::<Parent>();
+        while let Some(p) = parent {
+            if let Some(parent_transform) = scene.get::<Transform>(p.get()) {
+                transform = parent_transform.mul_transform(transform);
+            }
+            parent = scene.get::<Parent>(p.get());
+        }
+
+        let surface = Surface::of(entity.get::<Name>(), entity.get::<GltfExtras>()).or_else(|| {
+            let node = scene.get_entity(entity.get::<Parent>()?.get())?;
+            Surface::of(node.get::<Name>(), node.get::<GltfExtras>())
+        });
+
+        colliders.push((
+            collider,
+            Transform::from_translation(MAP_OFFSET).mul_transform(transform),
+            surface,
+        ));
+    }
+
+    for (collider, transform, surface) in colliders {
+        let mut entity = app.world.spawn((
+            Name::new(MAP_COLLIDER),
+            TransformBundle::from_transform(transform),
+            collider,
+            RigidBody::Static,
+            CollisionLayers::new(
+                [PhysicsLayers::Ground],
+                [PhysicsLayers::Player, PhysicsLayers::Ghost],
+            ),
+            MapEntityMarker,
+        ));
+        if let Some(surface) = surface {
+            entity.insert(surface);
+        }
+    }
+
+    Ok(())
+}
+
+/// Spawns the player with a running clock, checkpoints, jumppads and the goal, without models.
+fn spawn_run(mut commands: Commands, map: Res<Map>) -> Entity {
+    let player = commands
+        .spawn((
+            player_bundle(&map),
+            MapDuration::new(),
+            Splits::default(),
+            // The inputs are pushed by `HeadlessGame::step` right before they are needed.
+            ReplayPlayback::new(Replay::new(&map)),
+        ))
+        .id();
+
+    for (index, checkpoint) in map.checkpoints.iter().enumerate() {
+        commands.spawn((
+            TransformBundle::from_transform(checkpoint_transform(checkpoint)),
+            checkpoint_bundle(index),
+        ));
+    }
+
+    for pad in map.pads.iter().flatten() {
+        commands.spawn((
+            TransformBundle::from_transform(Transform::from_translation(pad.pos)),
+            jumppad_bundle(pad),
+        ));
+    }
+
+    commands.spawn((
+        Name::new("portal"),
+        TransformBundle::from_transform(goal_transform(&map)),
+        goal_collider(),
+        Goal,
+        MapEntityMarker,
+    ));
+
+    player
+}
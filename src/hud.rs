@@ -0,0 +1,167 @@
+//! The in-run HUD: speed, acceleration multiplier, air jumps, ground state, time and checkpoints.
+//! Where it is shown and what it shows is part of the [`Settings`].
+
+use bevy::prelude::*;
+use bevy_egui::{
+    egui::{self, Align2, Color32, Frame, Margin, RichText},
+    EguiContexts,
+};
+use bevy_xpbd_3d::prelude::LinearVelocity;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    character_controller::{AccelerationMultiplier, AirJumps, Grounded, JumpCount, Sliding},
+    checkpoint::Checkpoint,
+    settings::Settings,
+    timing::{format_time, MapDuration},
+    Player, State,
+};
+
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, display_hud.run_if(in_state(State::Playing)));
+    }
+}
+
+/// Screen corner the HUD is anchored to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum HudCorner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl HudCorner {
+    pub const ALL: [HudCorner; 4] = [
+        HudCorner::TopLeft,
+        HudCorner::TopRight,
+        HudCorner::BottomLeft,
+        HudCorner::BottomRight,
+    ];
+
+    fn anchor(self) -> (Align2, [f32; 2]) {
+        match self {
+            HudCorner::TopLeft => (Align2::LEFT_TOP, [20., 20.]),
+            // Below the lap counter.
+            HudCorner::TopRight => (Align2::RIGHT_TOP, [-20., 70.]),
+            HudCorner::BottomLeft => (Align2::LEFT_BOTTOM, [20., -20.]),
+            HudCorner::BottomRight => (Align2::RIGHT_BOTTOM, [-20., -20.]),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HudSettings {
+    pub visible: bool,
+    pub corner: HudCorner,
+    /// Text size in points.
+    pub size: f32,
+    pub speed: bool,
+    pub multiplier: bool,
+    pub air_jumps: bool,
+    pub ground_state: bool,
+    pub timer: bool,
+    pub checkpoints: bool,
+    /// Draw the paths of the run and the ghosts, coloured by speed.
+    pub trails: bool,
+}
+
+impl Default for HudSettings {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            corner: HudCorner::BottomLeft,
+            size: 20.,
+            speed: true,
+            multiplier: true,
+            air_jumps: true,
+            ground_state: true,
+            timer: true,
+            checkpoints: true,
+            trails: false,
+        }
+    }
+}
+
+fn display_hud(
+    mut contexts: EguiContexts,
+    settings: Res<Settings>,
+    player: Query<
+        (
+            &LinearVelocity,
+            &AccelerationMultiplier,
+            &JumpCount,
+            &AirJumps,
+            Has<Grounded>,
+            Has<Sliding>,
+            Option<&MapDuration>,
+        ),
+        With<Player>,
+    >,
+    checkpoints: Query<&Checkpoint>,
+) {
+    let hud = &settings.hud;
+    if !hud.visible {
+        return;
+    }
+    let Ok((velocity, multiplier, jump_count, air_jumps, is_grounded, is_sliding, duration)) =
+        player.get_single()
+    else {
+        return;
+    };
+
+    let text = |text: String| RichText::new(text).size(hud.size).color(Color32::WHITE);
+    let (align, offset) = hud.corner.anchor();
+
+    egui::Area::new("hud")
+        .anchor(align, offset)
+        .interactable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            Frame {
+                inner_margin: Margin::same(10.),
+                fill: Color32::from_black_alpha(120),
+                ..Default::default()
+            }
+            .show(ui, |ui| {
+                if hud.timer {
+                    // Before the countdown ends the clock hasn't started yet.
+                    let elapsed = duration.map(MapDuration::elapsed).unwrap_or_default();
+                    ui.label(text(format_time(elapsed)));
+                }
+                if hud.speed {
+                    ui.label(text(format!("Speed {:.1}", velocity.0.xz().length())));
+                }
+                if hud.multiplier {
+                    ui.label(text(format!("Multiplier x{:.2}", multiplier.0)));
+                }
+                if hud.air_jumps {
+                    // A jump off the ground gives back every air jump.
+                    let remaining = if is_grounded || is_sliding {
+                        air_jumps.0
+                    } else {
+                        air_jumps.0.saturating_sub(jump_count.0)
+                    };
+                    ui.label(text(format!("Air jumps {remaining}/{}", air_jumps.0)));
+                }
+                if hud.ground_state {
+                    let (state, color) = if is_grounded {
+                        ("Grounded", Color32::LIGHT_GREEN)
+                    } else if is_sliding {
+                        ("Sliding", Color32::GOLD)
+                    } else {
+                        ("Airborne", Color32::LIGHT_BLUE)
+                    };
+                    ui.label(text(state.into()).color(color));
+                }
+                if hud.checkpoints {
+                    let reached = checkpoints.iter().filter(|c| c.reached).count();
+                    let total = checkpoints.iter().count();
+                    ui.label(text(format!("Checkpoints {reached}/{total}")));
+                }
+            });
+        });
+}
@@ -1,12 +1,18 @@
+use std::time::Duration;
+
 use bevy::prelude::*;
 use bevy_xpbd_3d::prelude::LinearVelocity;
 
-use crate::{camera::LeashedCamera, character_controller::JumpCount};
+use crate::{
+    camera::LeashedCamera,
+    character_controller::{JumpCount, MovementAction, TickInput},
+    Player,
+};
 
 /// Contains spawn location and checkpoint location
 /// Should be initialized to both being spawn.
 /// (checkpoint, spawn)
-#[derive(Component, Default)]
+#[derive(Component, Default, Clone)]
 pub struct ResetSnapshot {
     /// Position when going through the checkpoint
     pub pos: Vec3,
@@ -16,29 +22,40 @@ pub struct ResetSnapshot {
     pub camera: (f32, f32),
     /// Jump count when passing checkpoint
     pub jump_count: u32,
+    /// Where the ghosts were on their tracks when passing the checkpoint, see [`crate::ghost::GhostDelay`].
+    pub ghost_time: Duration,
 }
 
+/// Applies [`MovementAction::Reset`] from the controller's [`TickInput`].
+/// Runs on the simulation tick, so replays reset at exactly the same point.
 pub fn reset_to_checkpoint(
-    keyboard_input: Res<Input<KeyCode>>,
     mut query: Query<(
+        &TickInput,
         &mut Transform,
         &mut LinearVelocity,
         &mut JumpCount,
         &ResetSnapshot,
+        Has<Player>,
     )>,
     mut camera: Query<&mut LeashedCamera>,
 ) {
-    if keyboard_input.just_pressed(KeyCode::Back) {
-        for (mut t, mut lv, mut jc, res) in &mut query {
-            t.translation = res.pos;
-            lv.0 = res.vel;
-            jc.0 = res.jump_count;
+    for (input, mut t, mut lv, mut jc, res, is_player) in &mut query {
+        if !input.actions.contains(&MovementAction::Reset) {
+            continue;
+        }
+
+        t.translation = res.pos;
+        lv.0 = res.vel;
+        jc.0 = res.jump_count;
+
+        if !is_player {
+            continue;
+        }
 
-            // Would probably be better with events
-            for mut cam in &mut camera {
-                cam.yaw = res.camera.0;
-                cam.pitch = res.camera.1;
-            }
+        // Would probably be better with events
+        for mut cam in &mut camera {
+            cam.yaw = res.camera.0;
+            cam.pitch = res.camera.1;
         }
     }
 }
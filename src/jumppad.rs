@@ -1,19 +1,48 @@
 use bevy::prelude::*;
-use bevy_xpbd_3d::prelude::{CollidingEntities, LinearVelocity};
+use bevy_xpbd_3d::prelude::{Collider, CollidingEntities, LinearVelocity, RigidBody, Sensor};
 
 use crate::{
-    character_controller::{Grounded, JumpCount},
-    Player,
+    character_controller::{CharacterController, JumpCount},
+    map::{self, Map},
+    MapEntityMarker,
 };
 
 #[derive(Component)]
 pub struct Jumppad(pub f32);
 
+/// The simulated part of a jumppad, without its model or transform.
+pub fn jumppad_bundle(pad: &map::Jumppad) -> impl Bundle {
+    (
+        Collider::cylinder(0.6, 4.8),
+        Sensor,
+        RigidBody::Static,
+        MapEntityMarker,
+        Jumppad(pad.strength),
+    )
+}
+
+pub fn spawn_pads(commands: &mut Commands, map: &Map, model: &Handle<Scene>) {
+    for pad in map.pads.iter().flatten() {
+        commands.spawn((
+            SceneBundle {
+                scene: model.clone_weak(),
+                transform: Transform::from_translation(pad.pos),
+                ..Default::default()
+            },
+            jumppad_bundle(pad),
+        ));
+    }
+}
+
+/// Applies to ghosts as well, so their replays stay in sync.
 pub fn apply_jumppad_boost(
-    mut player: Query<(Entity, &mut LinearVelocity, &mut JumpCount), With<Player>>,
+    mut controllers: Query<
+        (Entity, &mut LinearVelocity, &mut JumpCount),
+        With<CharacterController>,
+    >,
     pads: Query<(&CollidingEntities, &Jumppad)>,
 ) {
-    if let Ok((pe, mut linvel, mut jc)) = player.get_single_mut() {
+    for (pe, mut linvel, mut jc) in &mut controllers {
         for (colliding, pad) in &pads {
             if colliding.contains(&pe) {
                 linvel.y = pad.0;
@@ -1,8 +1,7 @@
 use std::{
+    cmp::Ordering,
     collections::HashMap,
-    fs::File,
-    io::{Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use bevy::prelude::*;
@@ -12,17 +11,132 @@ use bevy_egui::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{map::Map, State};
+use crate::{
+    map::Map,
+    profile::{default_profile, MovementProfile, DEFAULT_PROFILE},
+    replay::{fnv1a, Replay, ReplayRecorder},
+    splits::Split,
+    storage::{self, unix_time, StorageErrors},
+    timing::format_seconds,
+    viewer::WatchedReplays,
+    Player, State, StateOneshots,
+};
 pub struct LeaderboardPlugin;
 
+/// Entries kept per map and profile. Slower runs and their replays are dropped.
+const MAX_ENTRIES: usize = 50;
+
+const HIGHSCORES_FILE: &str = "highscores.json";
+
 #[derive(Event)]
 pub enum LeaderboardEvent {
-    SaveLeaderboardData(String, f32),
+    SaveLeaderboardData(String, Highscore),
+}
+
+/// One finished run on the leaderboard.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Highscore {
+    pub name: String,
+    /// Seconds since the unix epoch, 0 if unknown.
+    pub timestamp: u64,
+    /// Total time in seconds.
+    pub time: f32,
+    /// Fastest lap in seconds, on maps with more than one lap.
+    pub best_lap: Option<f32>,
+    pub splits: Vec<Split>,
+    /// The map's [`MovementProfile`], times are only ranked against the same one.
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    /// [`MovementProfile::hash`] of the profile the run was driven with.
+    /// Times are only ranked against runs with the same one, editing a profile starts a new table.
+    pub controller_hash: u64,
+    /// Id of the run's replay, see [`replay_file`].
+    pub replay: Option<String>,
+}
+
+/// Highscores used to be stored as plain times.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredHighscore {
+    Time(f32),
+    Entry(Highscore),
+}
+
+#[derive(Deserialize)]
+struct StoredHighscores {
+    maps: HashMap<String, Vec<StoredHighscore>>,
+}
+
+impl From<StoredHighscores> for MapHighscores {
+    fn from(stored: StoredHighscores) -> Self {
+        let maps = stored
+            .maps
+            .into_iter()
+            .map(|(map, entries)| {
+                let entries = entries
+                    .into_iter()
+                    .map(|entry| match entry {
+                        StoredHighscore::Time(time) => Highscore {
+                            time,
+                            profile: DEFAULT_PROFILE.into(),
+                            ..Default::default()
+                        },
+                        StoredHighscore::Entry(highscore) => highscore,
+                    })
+                    .collect();
+                (map, entries)
+            })
+            .collect();
+        Self { maps }
+    }
+}
+
+/// Name stored with new highscores.
+#[derive(Resource)]
+pub struct PlayerName(pub String);
+
+impl Default for PlayerName {
+    fn default() -> Self {
+        let name = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_else(|_| "Player".into());
+        Self(name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortColumn {
+    Name,
+    Time,
+    BestLap,
+    Date,
+}
+
+/// How the leaderboard tables are sorted and cut off.
+struct LeaderboardView {
+    sort: SortColumn,
+    descending: bool,
+    top_n: usize,
+    /// Replay ids of the entries picked to be watched together, in the order they were picked.
+    compared: Vec<String>,
+}
+
+impl Default for LeaderboardView {
+    fn default() -> Self {
+        Self {
+            sort: SortColumn::Time,
+            descending: false,
+            top_n: 10,
+            compared: vec![],
+        }
+    }
 }
 
 impl Plugin for LeaderboardPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<LeaderboardEvent>()
+            .init_resource::<PlayerName>()
             .add_systems(Startup, load_highscores)
             .add_systems(
                 Update,
@@ -37,45 +151,202 @@ impl Plugin for LeaderboardPlugin {
     }
 }
 
-fn load_highscores(mut commands: Commands) {
+/// Path of a replay referenced by a leaderboard entry, in the user data directory.
+pub fn replay_file(id: &str) -> PathBuf {
+    Path::new("replays").join(format!("{id}.replay"))
+}
+
+/// Formats a unix timestamp as a `YYYY-MM-DD` date.
+fn format_date(timestamp: u64) -> String {
+    if timestamp == 0 {
+        return "-".into();
+    }
+
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (timestamp / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn load_highscores(mut commands: Commands, mut errors: ResMut<StorageErrors>) {
     #[cfg(target_arch = "wasm32")]
     {
         commands.init_resource::<MapHighscores>();
         return;
     }
 
-    let path = Path::new("maps").join("highscores.json");
-    if !path.exists() {
-        File::create(path).unwrap();
-        commands.init_resource::<MapHighscores>();
-        return;
-    } else {
-        let mut file = File::open(path).unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        let highscores = serde_json::from_str::<MapHighscores>(&contents);
-        match highscores {
-            Ok(highscores) => {
-                commands.insert_resource(highscores);
+    errors.report(storage::migrate_legacy(
+        &Path::new("maps").join(HIGHSCORES_FILE),
+        HIGHSCORES_FILE,
+    ));
+    let highscores: MapHighscores = storage::load_json_or_default(HIGHSCORES_FILE, &mut errors);
+    commands.insert_resource(highscores);
+}
+
+fn compare(a: &Highscore, b: &Highscore, column: SortColumn) -> Ordering {
+    // Floats have no ord, so use partial
+    match column {
+        SortColumn::Name => a.name.cmp(&b.name),
+        SortColumn::Time => a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal),
+        SortColumn::BestLap => a
+            .best_lap
+            .partial_cmp(&b.best_lap)
+            .unwrap_or(Ordering::Equal),
+        SortColumn::Date => a.timestamp.cmp(&b.timestamp),
+    }
+}
+
+/// Shows the entries of one map as a table.
+/// Returns the entries whose replays should be watched, if a watch button was clicked.
+fn highscore_table<'a>(
+    ui: &mut egui::Ui,
+    id: &str,
+    entries: &[&'a Highscore],
+    view: &mut LeaderboardView,
+) -> Vec<&'a Highscore> {
+    let mut sorted = entries.to_vec();
+    sorted.sort_by(|a, b| {
+        let ordering = compare(a, b, view.sort);
+        if view.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+    sorted.truncate(view.top_n);
+
+    let show_laps = entries.iter().any(|h| h.best_lap.is_some());
+    let mut watch = vec![];
+
+    egui::Grid::new(id).striped(true).show(ui, |ui| {
+        ui.label("#");
+        let mut columns = vec![
+            (SortColumn::Name, "Name"),
+            (SortColumn::Time, "Time"),
+            (SortColumn::Date, "Date"),
+        ];
+        if show_laps {
+            columns.insert(2, (SortColumn::BestLap, "Best lap"));
+        }
+        for (column, label) in columns {
+            let selected = view.sort == column;
+            let label = match (selected, view.descending) {
+                (true, false) => format!("{label} ^"),
+                (true, true) => format!("{label} v"),
+                _ => label.to_string(),
+            };
+            if ui.selectable_label(selected, label).clicked() {
+                if selected {
+                    view.descending = !view.descending;
+                } else {
+                    view.sort = column;
+                    view.descending = false;
+                }
             }
-            Err(_) => commands.init_resource::<MapHighscores>(),
         }
+        ui.end_row();
+
+        for (i, h) in sorted.into_iter().enumerate() {
+            ui.label(format!("{}", i + 1));
+            ui.label(if h.name.is_empty() {
+                "-"
+            } else {
+                h.name.as_str()
+            });
+            ui.label(format_seconds(h.time));
+            if show_laps {
+                ui.label(h.best_lap.map_or("-".into(), format_seconds));
+            }
+            ui.label(format_date(h.timestamp));
+            if let Some(replay) = &h.replay {
+                if ui.button("Watch").clicked() {
+                    watch = vec![h];
+                }
+                let index = view.compared.iter().position(|id| id == replay);
+                let mut checked = index.is_some();
+                if ui.checkbox(&mut checked, "Compare").changed() {
+                    match index {
+                        Some(index) => {
+                            view.compared.remove(index);
+                        }
+                        None => view.compared.push(replay.clone()),
+                    }
+                }
+            }
+            ui.end_row();
+        }
+    });
+
+    // Only entries of the same map and profile can be watched together.
+    let compared: Vec<_> = view
+        .compared
+        .iter()
+        .filter_map(|id| entries.iter().find(|h| h.replay.as_ref() == Some(id)))
+        .copied()
+        .collect();
+    if compared.len() > 1
+        && ui
+            .button(format!("Watch {} together", compared.len()))
+            .clicked()
+    {
+        watch = compared;
+    }
+
+    watch
+}
+
+/// Shows the entries of a profile on a map, those driven with an older version of it apart.
+/// `current` is the [`MovementProfile::hash`] of the profile, `None` if it can't be loaded anymore.
+fn profile_tables<'a>(
+    ui: &mut egui::Ui,
+    id: &str,
+    entries: impl Iterator<Item = &'a Highscore>,
+    current: Option<u64>,
+    view: &mut LeaderboardView,
+) -> Vec<&'a Highscore> {
+    let (entries, stale): (Vec<_>, Vec<_>) =
+        entries.partition(|h| Some(h.controller_hash) == current);
+
+    let mut watch = highscore_table(ui, id, &entries, view);
+    if !stale.is_empty() {
+        ui.collapsing(
+            format!("Older versions of the profile ({})", stale.len()),
+            |ui| {
+                let picked = highscore_table(ui, &format!("{id} stale"), &stale, view);
+                if !picked.is_empty() {
+                    watch = picked;
+                }
+            },
+        );
     }
+    watch
 }
 
 fn display_leaderboard(
+    mut commands: Commands,
     mut contexts: EguiContexts,
     highscores: Res<MapHighscores>,
     map: Option<Res<Map>>,
     mut state: ResMut<NextState<State>>,
+    oneshots: Res<StateOneshots>,
+    mut errors: ResMut<StorageErrors>,
+    mut view: Local<LeaderboardView>,
+    // Loaded once, the all maps view would read the profiles every frame otherwise.
+    mut profile_hashes: Local<HashMap<String, Option<u64>>>,
 ) {
     let ctx = contexts.ctx_mut();
-    // if there is a map, only display the current maps highscores.
-    if let Some(map) = map {
-        let mut map_highscores = highscores.maps.get(&map.name).unwrap().clone();
-        // Floats have no ord, so use partial
-        map_highscores.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut watch = None;
 
+    // if there is a map, only display the current maps highscores.
+    if let Some(map) = &map {
         egui::Area::new("highscores").show(ctx, |ui| {
             Frame {
                 outer_margin: Margin::symmetric(300., 0.),
@@ -86,8 +357,23 @@ fn display_leaderboard(
             .show(ui, |ui| {
                 ui.vertical_centered(|ui| {
                     ui.label("Highscores:");
-                    for h in map_highscores {
-                        ui.label(format!("{}", h));
+                    ui.add(
+                        egui::DragValue::new(&mut view.top_n)
+                            .clamp_range(1..=MAX_ENTRIES)
+                            .prefix("Top "),
+                    );
+                    if map.profile != DEFAULT_PROFILE {
+                        ui.label(format!("Profile: {}", map.profile));
+                    }
+                    let picked = profile_tables(
+                        ui,
+                        &map.name,
+                        highscores.entries(&map.name, &map.profile),
+                        Some(map.movement.hash()),
+                        &mut view,
+                    );
+                    if !picked.is_empty() {
+                        watch = Some((map.name.clone(), picked.into_iter().cloned().collect()));
                     }
                 });
             });
@@ -110,13 +396,40 @@ fn display_leaderboard(
                         ui.label("Highscores:");
                     }
 
-                    for (map, highscores) in &highscores.maps {
-                        let mut map_highscores = highscores.clone();
-                        // Floats have no ord, so use partial
-                        map_highscores.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                    ui.add(
+                        egui::DragValue::new(&mut view.top_n)
+                            .clamp_range(1..=MAX_ENTRIES)
+                            .prefix("Top "),
+                    );
+
+                    let mut maps: Vec<_> = highscores.maps.iter().collect();
+                    maps.sort_by(|a, b| a.0.cmp(b.0));
+                    for (map, entries) in maps {
                         ui.collapsing(map.replace(".glb", ""), |ui| {
-                            for h in map_highscores {
-                                ui.label(format!("{}", h));
+                            let mut profiles: Vec<&str> =
+                                entries.iter().map(|h| h.profile.as_str()).collect();
+                            profiles.sort_unstable();
+                            profiles.dedup();
+                            for profile in profiles {
+                                if profile != DEFAULT_PROFILE {
+                                    ui.label(format!("Profile: {profile}"));
+                                }
+                                let current =
+                                    *profile_hashes.entry(profile.to_string()).or_insert_with(
+                                        || MovementProfile::load(profile).ok().map(|p| p.hash()),
+                                    );
+                                let id = format!("{map} {profile}");
+                                let picked = profile_tables(
+                                    ui,
+                                    &id,
+                                    highscores.entries(map, profile),
+                                    current,
+                                    &mut view,
+                                );
+                                if !picked.is_empty() {
+                                    watch =
+                                        Some((map.clone(), picked.into_iter().cloned().collect()));
+                                }
                             }
                         });
                     }
@@ -130,24 +443,99 @@ fn display_leaderboard(
             });
         });
     }
+
+    let Some((map_name, highscores)) = watch else {
+        return;
+    };
+    let map = match Map::load(&map_name) {
+        Ok(map) => map,
+        Err(e) => {
+            println!("Could not load map {map_name}: {e}");
+            return;
+        }
+    };
+    let mut replays = vec![];
+    for highscore in highscores {
+        let Some(id) = highscore.replay.as_deref() else {
+            continue;
+        };
+        let Some(replay) = errors.report(Replay::load(&replay_file(id))).flatten() else {
+            continue;
+        };
+        if !replay.is_watchable_on(&map) {
+            println!("Replay was recorded with an older version of the game, can't watch it.");
+            continue;
+        }
+        let name = if highscore.name.is_empty() {
+            "-"
+        } else {
+            highscore.name.as_str()
+        };
+        replays.push((format!("{name} {}", format_seconds(highscore.time)), replay));
+    }
+    if replays.is_empty() {
+        return;
+    }
+
+    commands.run_system(oneshots.unload);
+    commands.insert_resource(map);
+    commands.insert_resource(WatchedReplays(replays));
+    commands.run_system(oneshots.load_map);
+    commands.run_system(oneshots.watch_replay);
+    state.set(State::Replay);
 }
 
-fn add_highscore(mut er: EventReader<LeaderboardEvent>, mut leaderboard: ResMut<MapHighscores>) {
+fn add_highscore(
+    mut er: EventReader<LeaderboardEvent>,
+    mut leaderboard: ResMut<MapHighscores>,
+    name: Res<PlayerName>,
+    recorder: Query<&ReplayRecorder, With<Player>>,
+    mut errors: ResMut<StorageErrors>,
+) {
     for e in er.read() {
         match e {
-            LeaderboardEvent::SaveLeaderboardData(map, time) => {
-                let map_scores = leaderboard.maps.get_mut(map);
-                if let Some(map_scores) = map_scores {
-                    map_scores.push(*time);
-                } else {
-                    leaderboard.maps.insert(map.clone(), vec![*time]);
+            LeaderboardEvent::SaveLeaderboardData(map, highscore) => {
+                let mut entry = Highscore {
+                    name: name.0.clone(),
+                    timestamp: unix_time(),
+                    ..highscore.clone()
+                };
+
+                #[cfg(not(target_arch = "wasm32"))]
+                if let Ok(recorder) = recorder.get_single() {
+                    // Runs finished within the same second only differ by their input.
+                    let replay = recorder.0.encode();
+                    let id = format!("{map}-{}-{:016x}", entry.timestamp, fnv1a(&replay));
+                    if errors
+                        .report(storage::write(replay_file(&id), &replay))
+                        .is_some()
+                    {
+                        entry.replay = Some(id);
+                    }
                 }
 
-                let serialized = serde_json::to_string(leaderboard.into_inner()).unwrap();
+                let (profile, hash) = (entry.profile.clone(), entry.controller_hash);
+                let map_scores = leaderboard.maps.entry(map.clone()).or_default();
+                map_scores.push(entry);
+                map_scores.sort_by(|a, b| compare(a, b, SortColumn::Time));
+
+                let mut ranked = 0;
+                let (kept, dropped): (Vec<_>, Vec<_>) = map_scores.drain(..).partition(|h| {
+                    if h.profile != profile || h.controller_hash != hash {
+                        return true;
+                    }
+                    ranked += 1;
+                    ranked <= MAX_ENTRIES
+                });
+                *map_scores = kept;
+                for dropped in dropped {
+                    if let Some(id) = dropped.replay {
+                        errors.report(storage::remove(replay_file(&id)));
+                    }
+                }
 
-                // Overwrite the file
-                let mut file = File::create("maps/highscores.json").unwrap();
-                _ = file.write_all(serialized.as_bytes());
+                #[cfg(not(target_arch = "wasm32"))]
+                errors.report(storage::save_json(HIGHSCORES_FILE, &*leaderboard));
                 break; // This should only be triggered once either way
             }
         }
@@ -155,6 +543,64 @@ fn add_highscore(mut er: EventReader<LeaderboardEvent>, mut leaderboard: ResMut<
 }
 
 #[derive(Default, Resource, Serialize, Deserialize)]
+#[serde(from = "StoredHighscores")]
 pub struct MapHighscores {
-    maps: HashMap<String, Vec<f32>>,
+    maps: HashMap<String, Vec<Highscore>>,
+}
+
+impl MapHighscores {
+    /// Entries stored for a map that were driven with a profile, with any version of it.
+    pub fn entries<'a>(
+        &'a self,
+        map: &str,
+        profile: &'a str,
+    ) -> impl Iterator<Item = &'a Highscore> + 'a {
+        self.maps
+            .get(map)
+            .into_iter()
+            .flatten()
+            .filter(move |h| h.profile == profile)
+    }
+
+    /// Entries stored for a map that were driven with the current version of its profile.
+    pub fn current_entries<'a>(&'a self, map: &'a Map) -> impl Iterator<Item = &'a Highscore> + 'a {
+        let hash = map.movement.hash();
+        self.entries(&map.name, &map.profile)
+            .filter(move |h| h.controller_hash == hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_times_are_migrated() {
+        let json = r#"{
+            "maps": {
+                "winter": [
+                    12.5,
+                    { "name": "fox", "timestamp": 1700000000, "time": 11.25, "profile": "default" }
+                ]
+            }
+        }"#;
+
+        let highscores: MapHighscores = serde_json::from_str(json).unwrap();
+        let entries: Vec<_> = highscores.entries("winter", DEFAULT_PROFILE).collect();
+
+        let [legacy, entry] = entries[..] else {
+            panic!("Expected both entries, got {}", entries.len());
+        };
+        assert_eq!(legacy.name, "");
+        assert_eq!(legacy.time, 12.5);
+        assert_eq!(legacy.timestamp, 0);
+        assert_eq!(legacy.profile, DEFAULT_PROFILE);
+        // Driven with unknown physics, it's never ranked with current runs.
+        assert_eq!(legacy.controller_hash, 0);
+        assert!(legacy.best_lap.is_none() && legacy.splits.is_empty() && legacy.replay.is_none());
+
+        assert_eq!(entry.name, "fox");
+        assert_eq!(entry.time, 11.25);
+        assert_eq!(entry.timestamp, 1700000000);
+    }
 }
@@ -1,6 +1,7 @@
 #![allow(clippy::type_complexity)]
 mod assets;
 mod audio;
+mod bindings;
 mod camera;
 mod character_controller;
 mod checkpoint;
@@ -8,39 +9,66 @@ mod debug;
 mod environment;
 mod events;
 mod ghost;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod headless;
+mod hud;
 mod input;
 mod jumppad;
 mod leaderboard;
 mod map;
 mod physics;
 mod player;
+mod profile;
+mod replay;
+mod replay_format;
 mod scene;
+mod settings;
+mod splits;
+mod storage;
+mod surface;
 mod timing;
+mod trail;
+mod tuning;
 mod ui;
 mod vfx;
+mod viewer;
 
 use assets::Animations;
 use bevy::{
     core_pipeline::experimental::taa::TemporalAntiAliasPlugin,
     ecs::system::SystemId,
     prelude::*,
-    window::{close_on_esc, PresentMode},
+    window::{close_on_esc, CursorGrabMode},
 };
 use bevy_egui::EguiPlugin;
 
-use bevy_xpbd_3d::prelude::*;
+use bevy_xpbd_3d::{prelude::*, PhysicsSet};
+use bindings::BindingsPlugin;
 use camera::{spawn_camera, LeashedCameraPlugin};
-use character_controller::CharacterControllerPlugin;
-use checkpoint::{Checkpoint, Goal};
+use character_controller::{CharacterControllerPlugin, ControllerSet};
+use checkpoint::{
+    display_wrong_checkpoint, goal_collider, goal_transform, highlight_next_checkpoint,
+    spawn_checkpoints, Goal,
+};
 use environment::spawn_sky;
 use events::{EventPlugin, StateEvents};
-use jumppad::Jumppad;
+use hud::HudPlugin;
+use jumppad::spawn_pads;
 use leaderboard::LeaderboardPlugin;
-use map::{add_collision_layers, all_maps, spawn_map, Map};
-use physics::PhysicsLayers;
-use player::{rotate_player_model, spawn_player, update_player_animation};
+use map::{add_collision_layers, all_maps, spawn_map, Map, MapError};
+use physics::{InterpolationPlugin, TICK_RATE};
+use player::{rotate_player_model, spawn_player, update_animations};
+use replay::ReplayPlugin;
 use scene::{setup_scene_once_loaded, unload};
-use ui::{spawn_countdown_display, to_main_menu};
+use settings::{Settings, SettingsPlugin};
+use splits::SplitsPlugin;
+use storage::StorageErrors;
+use trail::TrailPlugin;
+use tuning::{MovementTuning, Tuned, TuningPlugin};
+use ui::{
+    display_lap, on_pause, on_resume, pause_run, restart_run, spawn_countdown_display, ui_paused,
+    CountdownDisplay,
+};
 
 #[cfg(not(target_arch = "wasm32"))]
 use bevy_hanabi::prelude::*;
@@ -50,14 +78,20 @@ use vfx::{create_ground_effect, create_portal};
 use crate::{
     assets::AssetHandles,
     audio::AudioPlugin,
-    checkpoint::CheckpointPlugin,
+    camera::LeashedCamera,
+    character_controller::{Airborne, GroundContact, Grounded, Sliding},
+    checkpoint::{Checkpoint, CheckpointPlugin},
     debug::debug_things,
     ghost::GhostPlugin,
     input::reset_to_checkpoint,
     jumppad::apply_jumppad_boost,
-    timing::{countdown_timer, display_countdown, tick},
+    leaderboard::LeaderboardEvent,
+    player::{player_bundle, start_snapshot, start_transform},
+    splits::Splits,
+    timing::{advance_clock, countdown_timer, display_countdown, tick, MapDuration},
     ui::{setup_ui, ui_finish, ui_mainscreen},
     vfx::VfxPlugin,
+    viewer::{watch_replay, ViewerPlugin, WatchedReplays},
 };
 
 #[derive(Component)]
@@ -66,6 +100,8 @@ pub struct Player;
 #[derive(Resource)]
 pub struct Maps {
     maps: Vec<String>,
+    /// Maps that failed to load, shown in the main menu instead of crashing.
+    broken: Vec<(String, MapError)>,
 }
 
 /// Marker for entities that must be unloaded when switching or resetting map.
@@ -82,75 +118,112 @@ pub enum State {
     Playing,
     Finished,
     Leaderboard,
+    /// Picking the ghosts to race against before a run on the [`Map`].
+    GhostSelect,
+    /// Rebinding the [`bindings::InputAction`]s.
+    Controls,
+    Settings,
+    /// A run in progress with the time, physics and countdown frozen.
+    Paused,
+    /// Watching a replay in the viewer, there is no player.
+    Replay,
 }
 
 #[derive(Resource)]
 pub struct StateOneshots {
     load_map: SystemId,
     unload: SystemId,
+    /// Spawns the [`viewer::WatchedReplays`] to watch, run after `load_map`.
+    watch_replay: SystemId,
+    /// Starts the current map over.
+    restart: SystemId,
 }
 
 pub fn bevy_main() {
     let mut app = App::new();
 
-    #[cfg(not(target_os = "macos"))]
-    let present_mode = PresentMode::Mailbox;
-    #[cfg(target_os = "macos")]
-    let present_mode = PresentMode::Fifo;
-
-    app.add_state::<State>()
-        .add_plugins((
-            DefaultPlugins
-                .set(WindowPlugin {
-                    primary_window: Some(Window {
-                        #[cfg(not(target_arch = "wasm32"))]
-                        present_mode,
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                })
-                .set(AssetPlugin {
-                    mode: AssetMode::Unprocessed,
+    // Loaded before the window is created, so it opens with the right present mode.
+    let mut errors = StorageErrors::default();
+    let settings = Settings::load(&mut errors);
+
+    app.add_plugins((
+        DefaultPlugins
+            .set(WindowPlugin {
+                primary_window: Some(Window {
+                    #[cfg(not(target_arch = "wasm32"))]
+                    present_mode: settings.present_mode(),
                     ..Default::default()
                 }),
-            PhysicsPlugins::default(),
-            CharacterControllerPlugin,
-            AudioPlugin,
-            GhostPlugin,
-            EguiPlugin,
-            //FramepacePlugin,
-            CheckpointPlugin,
-            VfxPlugin,
-            EventPlugin,
-            LeaderboardPlugin,
-        ))
-        .add_plugins(LeashedCameraPlugin)
-        .add_systems(Startup, (setup, setup_ui, setup_oneshots))
-        .add_systems(PreUpdate, add_collision_layers)
-        .add_systems(
-            Update,
-            (close_on_esc, ui_mainscreen).run_if(in_state(State::Mainscreen)),
-        )
-        .add_systems(
-            Update,
-            (
-                debug_things,
-                reset_to_checkpoint,
-                setup_scene_once_loaded,
-                update_player_animation,
-                rotate_player_model,
-                apply_jumppad_boost,
-                countdown_timer,
-                tick,
-                display_countdown,
-                to_main_menu,
-            )
-                .run_if(in_state(State::Playing)),
+                ..Default::default()
+            })
+            .set(AssetPlugin {
+                mode: AssetMode::Unprocessed,
+                ..Default::default()
+            }),
+        GameplayPlugin,
+        AudioPlugin,
+        GhostPlugin,
+        EguiPlugin,
+        //FramepacePlugin,
+        VfxPlugin,
+        EventPlugin,
+        LeaderboardPlugin,
+        SplitsPlugin,
+    ))
+    .add_plugins((
+        LeashedCameraPlugin,
+        BindingsPlugin,
+        SettingsPlugin,
+        TuningPlugin,
+        HudPlugin,
+        ViewerPlugin,
+        TrailPlugin,
+    ))
+    .insert_resource(settings)
+    .insert_resource(errors)
+    .add_systems(Startup, (setup, setup_ui, setup_oneshots))
+    .add_systems(
+        Update,
+        (close_on_esc, ui_mainscreen).run_if(in_state(State::Mainscreen)),
+    )
+    .add_systems(
+        Update,
+        (
+            debug_things,
+            setup_scene_once_loaded,
+            update_animations,
+            rotate_player_model,
+            display_countdown,
+            highlight_next_checkpoint,
+            display_wrong_checkpoint,
+            display_lap,
+            pause_run,
         )
-        .add_systems(
-            Update,
-            (close_on_esc, ui_finish).run_if(in_state(State::Finished)),
-        );
+            .run_if(in_state(State::Playing)),
+    )
+    // On the fixed tick, so the clock starts exactly on a tick boundary.
+    .add_systems(
+        FixedUpdate,
+        (tick, countdown_timer)
+            .chain()
+            .before(ControllerSet::Input)
+            .run_if(in_state(State::Playing)),
+    )
+    .add_systems(
+        Update,
+        restart_run.run_if(
+            in_state(State::Playing)
+                .or_else(in_state(State::Finished))
+                .or_else(in_state(State::Paused)),
+        ),
+    )
+    .add_systems(OnEnter(State::Paused), on_pause)
+    .add_systems(OnExit(State::Paused), on_resume)
+    .add_systems(Update, ui_paused.run_if(in_state(State::Paused)))
+    .add_systems(
+        Update,
+        (close_on_esc, ui_finish).run_if(in_state(State::Finished)),
+    );
 
     #[cfg(not(target_arch = "wasm32"))]
     {
@@ -168,9 +241,48 @@ pub fn bevy_main() {
     app.run();
 }
 
+/// Everything needed to simulate a run: physics, the character controller,
+/// replays, checkpoints, jumppads and the goal.
+/// Has no rendering, audio or UI, so it also runs headless.
+pub struct GameplayPlugin;
+
+impl Plugin for GameplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_state::<State>()
+            .add_plugins((
+                PhysicsPlugins::new(FixedUpdate),
+                InterpolationPlugin,
+                CharacterControllerPlugin,
+                ReplayPlugin,
+                CheckpointPlugin,
+            ))
+            .add_event::<LeaderboardEvent>()
+            .insert_resource(Time::<Fixed>::from_hz(TICK_RATE))
+            // Physics runs once per fixed tick and advances by the tick's delta, capped at 1/30 s.
+            .insert_resource(Time::new_with(Physics::variable(1. / 30.)))
+            .add_systems(PreUpdate, add_collision_layers)
+            .add_systems(
+                FixedUpdate,
+                (
+                    reset_to_checkpoint
+                        .after(ControllerSet::Input)
+                        .before(ControllerSet::Movement),
+                    apply_jumppad_boost
+                        .after(ControllerSet::Movement)
+                        .before(PhysicsSet::Prepare),
+                    advance_clock
+                        .after(ControllerSet::Input)
+                        .before(ControllerSet::Movement),
+                )
+                    .run_if(in_state(State::Playing)),
+            );
+    }
+}
+
 pub fn load_map(
     mut commands: Commands,
     map: Res<Map>,
+    watched: Option<Res<WatchedReplays>>,
     asset_handles: Res<AssetHandles>,
     assetserver: Res<AssetServer>,
     #[cfg(not(target_arch = "wasm32"))] mut effects: ResMut<Assets<EffectAsset>>,
@@ -183,7 +295,11 @@ pub fn load_map(
         assetserver.load("Fox.gltf#Animation4"), // jump
     ]));
 
-    spawn_player(&map, &mut commands, &asset_handles);
+    // The viewer shows a replay instead.
+    let is_watching = watched.is_some();
+    if !is_watching {
+        spawn_player(&map, &mut commands, &asset_handles);
+    }
 
     spawn_camera(&mut commands, map.start_rotation.to_radians());
 
@@ -199,12 +315,10 @@ pub fn load_map(
             Name::new("portal"),
             ParticleEffectBundle {
                 effect: ParticleEffect::new(portal),
-                transform: Transform::from_translation(map.end_pos)
-                    .with_scale(Vec3::splat(3.))
-                    .with_rotation(Quat::from_rotation_y(map.end_rotation.to_radians())),
+                transform: goal_transform(&map),
                 ..Default::default()
             },
-            Collider::cuboid(10., 10., 3.),
+            goal_collider(),
             Goal,
             MapEntityMarker,
         ));
@@ -219,23 +333,7 @@ pub fn load_map(
             ))
             .insert(Name::new("effect"));
 
-        for checkpoint in &map.checkpoints {
-            commands.spawn((
-                SceneBundle {
-                    scene: asset_handles.tori.clone_weak(),
-                    transform: Transform::from_translation(checkpoint.pos)
-                        .with_scale(Vec3::splat(2.))
-                        .with_rotation(Quat::from_rotation_y(checkpoint.rot.to_radians())),
-                    ..Default::default()
-                },
-                Sensor,
-                CollisionLayers::new([PhysicsLayers::Sensor], [PhysicsLayers::Sensor]),
-                Collider::cuboid(10., 20., 3.),
-                RigidBody::Static,
-                Checkpoint { reached: false },
-                MapEntityMarker,
-            ));
-        }
+        spawn_checkpoints(&mut commands, &map, &asset_handles.tori);
     }
 
     let mesh = meshes.add(Mesh::from(shape::Torus::default()));
@@ -245,38 +343,82 @@ pub fn load_map(
     });
     // inside wasm the goal can't be particle effects as there are no compute shaders:
     #[cfg(target_arch = "wasm32")]
-    commands.spawn((
-        Name::new("portal"),
-        Collider::cuboid(10., 10., 3.),
-        Goal,
-        MapEntityMarker,
-    ));
-
-    if let Some(pads) = &map.pads {
-        for pad in pads {
-            commands.spawn((
-                SceneBundle {
-                    scene: asset_handles.pad.clone_weak(),
-                    transform: Transform::from_translation(pad.pos),
-                    ..Default::default()
-                },
-                Collider::cylinder(0.6, 4.8),
-                Sensor,
-                RigidBody::Static,
-                MapEntityMarker,
-                Jumppad(pad.strength),
-            ));
-        }
+    commands.spawn((Name::new("portal"), goal_collider(), Goal, MapEntityMarker));
+
+    spawn_pads(&mut commands, &map, &asset_handles.pad);
+
+    if !is_watching {
+        spawn_countdown_display(commands);
+    }
+}
+
+/// Starts the current map over in place. The player, checkpoints and countdown are
+/// reset to how [`load_map`] spawned them, without reloading the scene or its colliders.
+/// Ghosts follow the run's clock, so they start over with it. Tuned values are dropped.
+pub fn restart(
+    mut commands: Commands,
+    map: Res<Map>,
+    mut state: ResMut<NextState<State>>,
+    mut windows: Query<&mut Window>,
+    players: Query<Entity, With<Player>>,
+    mut checkpoints: Query<&mut Checkpoint>,
+    mut cameras: Query<&mut LeashedCamera>,
+    countdowns: Query<Entity, With<CountdownDisplay>>,
+    tuning: Option<ResMut<MovementTuning>>,
+) {
+    let start = start_transform(&map);
+    let snapshot = start_snapshot(&map);
+
+    for player in &players {
+        // The clock starts again when the countdown ends.
+        commands
+            .entity(player)
+            .remove::<(Grounded, Sliding, Airborne, MapDuration, Splits, Tuned)>()
+            .insert((
+                Position(start.translation),
+                LinearVelocity(Vec3::ZERO),
+                GroundContact::default(),
+            ))
+            .insert(player_bundle(&map));
     }
 
+    for mut checkpoint in &mut checkpoints {
+        checkpoint.reached = false;
+    }
+
+    // Otherwise the tuning panel would drive the player with its values again right away.
+    if let Some(mut tuning) = tuning {
+        tuning.profile = map.movement.clone();
+    }
+
+    for mut camera in &mut cameras {
+        (camera.yaw, camera.pitch) = snapshot.camera;
+    }
+
+    for mut window in &mut windows {
+        window.cursor.grab_mode = CursorGrabMode::Locked;
+        window.cursor.visible = false;
+    }
+    state.set(State::Playing);
+
+    for countdown in &countdowns {
+        commands.entity(countdown).despawn_recursive();
+    }
     spawn_countdown_display(commands);
 }
 
 pub fn setup_oneshots(world: &mut World) {
     let load_map = world.register_system(load_map);
     let unload = world.register_system(unload);
+    let watch_replay = world.register_system(watch_replay);
+    let restart = world.register_system(restart);
 
-    let oneshots = StateOneshots { load_map, unload };
+    let oneshots = StateOneshots {
+        load_map,
+        unload,
+        watch_replay,
+        restart,
+    };
 
     world.insert_resource(oneshots);
 }
@@ -289,13 +431,12 @@ pub fn setup(
     aserv: Res<AssetServer>,
     mut ew: EventWriter<StateEvents>,
 ) {
-    let maps = all_maps();
-    commands.insert_resource(Maps { maps });
+    let (maps, broken) = all_maps();
+    commands.insert_resource(Maps { maps, broken });
 
     commands.insert_resource(AssetHandles::load(&aserv));
 
     //pace.limiter = Limiter::from_framerate(30.);
-    commands.insert_resource(Time::new_with(Physics::variable(1. / 30.)));
 
     spawn_sky(commands, &mut meshes, &mut materials);
     ew.send(StateEvents::LoadMainscreen);
@@ -1,15 +1,31 @@
-use std::{fs::File, io::Read, path::Path};
+use std::{fmt, fs::File, io::Read, path::Path};
 
-use bevy::prelude::*;
+use bevy::{gltf::GltfExtras, prelude::*};
 use bevy_xpbd_3d::prelude::{
     AsyncSceneCollider, Collider, CollisionLayers, ComputedCollider, RigidBody, VHACDParameters,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{checkpoint, physics::PhysicsLayers, MapEntityMarker, MapMarker};
+use crate::{
+    checkpoint,
+    physics::PhysicsLayers,
+    profile::{default_profile, MovementProfile, ProfileError},
+    surface::Surface,
+    MapEntityMarker, MapMarker,
+};
+
+/// Version of the map format this build reads and writes.
+/// Maps without a `format_version` field predate versioning and are read as version 0.
+/// Bump it with every field that changes how a map plays, so older builds refuse such maps.
+pub const MAP_FORMAT_VERSION: u32 = 2;
+
+/// Fields added in format version 2: ordered checkpoints, laps and movement profiles.
+const VERSION_2_FIELDS: [&str; 3] = ["ordered_checkpoints", "laps", "profile"];
 
 #[derive(Resource, Debug, Serialize, Deserialize)]
 pub struct Map {
+    #[serde(default)]
+    pub format_version: u32,
     pub name: String,
     pub scene: Option<String>,
     pub file: String,
@@ -18,8 +34,24 @@ pub struct Map {
     pub end_rotation: f32,
     pub start_rotation: f32,
     pub checkpoints: Vec<Checkpoint>,
+    /// Checkpoints must be reached in the order they are listed in.
+    #[serde(default)]
+    pub ordered_checkpoints: bool,
+    /// Number of laps. With more than one, the goal is the start/finish line of a circuit.
+    #[serde(default = "default_laps")]
+    pub laps: u32,
     pub pads: Option<Vec<Jumppad>>,
     collidertype: Option<u32>,
+    /// Name of the [`MovementProfile`] in `profiles/` the map is driven with.
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    /// The loaded [`Map::profile`].
+    #[serde(skip)]
+    pub movement: MovementProfile,
+}
+
+fn default_laps() -> u32 {
+    1
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,9 +66,73 @@ pub struct Jumppad {
     pub strength: f32,
 }
 
-impl From<&str> for Map {
-    fn from(value: &str) -> Self {
-        Map::load(value)
+/// Reasons a map can fail to load.
+#[derive(Debug)]
+pub enum MapError {
+    /// There is no map with this name.
+    Missing,
+    /// The map file exists but couldn't be read.
+    Io(std::io::Error),
+    /// The map isn't valid json or doesn't match the map schema.
+    /// Line and column are 0 if the position is unknown.
+    Json {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// The map was written by a newer version of the game.
+    UnsupportedVersion(u32),
+    /// A field holds a value the game can't use.
+    OutOfRange { field: String, reason: &'static str },
+    /// The scene file the map refers to doesn't exist in `assets/`.
+    MissingScene(String),
+    /// The movement profile the map names couldn't be loaded.
+    Profile { name: String, error: ProfileError },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Missing => write!(f, "map doesn't exist"),
+            MapError::Io(e) => write!(f, "map could not be read: {e}"),
+            MapError::Json {
+                line: 0, message, ..
+            } => write!(f, "invalid map json: {message}"),
+            MapError::Json {
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "invalid map json at line {line}, column {column}: {message}"
+            ),
+            MapError::UnsupportedVersion(version) => write!(
+                f,
+                "map format version {version} is newer than the supported version {MAP_FORMAT_VERSION}"
+            ),
+            MapError::OutOfRange { field, reason } => write!(f, "`{field}` {reason}"),
+            MapError::MissingScene(file) => write!(f, "scene `{file}` doesn't exist"),
+            MapError::Profile { name, error } => write!(f, "movement profile `{name}`: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+impl From<serde_json::Error> for MapError {
+    fn from(e: serde_json::Error) -> Self {
+        // serde_json appends the position to its message, strip it as we report it separately.
+        let message = e.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(i) if e.line() > 0 => message[..i].to_string(),
+            _ => message,
+        };
+
+        MapError::Json {
+            line: e.line(),
+            column: e.column(),
+            message,
+        }
     }
 }
 
@@ -48,22 +144,154 @@ const STATIC_MAPS: [(&str, &str); 2] = [
 
 impl Map {
     #[cfg(target_arch = "wasm32")]
-    pub fn load(name: &str) -> Self {
-        let map = STATIC_MAPS.iter().find(|m| m.0 == name);
-        serde_json::from_str::<Map>(map.unwrap().1).expect("Map could not be loaded from json")
+    pub fn load(name: &str) -> Result<Self, MapError> {
+        let (_, contents) = STATIC_MAPS
+            .iter()
+            .find(|m| m.0 == name)
+            .ok_or(MapError::Missing)?;
+        Map::parse(contents)
     }
 
     #[cfg(not(target_arch = "wasm32"))]
-    pub fn load(name: &str) -> Self {
+    pub fn load(name: &str) -> Result<Self, MapError> {
         let path = Path::new("maps").join(name);
         if !path.exists() {
-            panic!("Map doesn't exist.");
+            return Err(MapError::Missing);
         }
 
-        let mut file = File::open(path).unwrap();
+        let mut file = File::open(path).map_err(MapError::Io)?;
         let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        serde_json::from_str::<Map>(&contents).expect("Map could not be loaded from json")
+        file.read_to_string(&mut contents).map_err(MapError::Io)?;
+
+        let map = Map::parse(&contents)?;
+
+        let scene = Path::new("assets").join(format!("{}.glb", map.file));
+        if !scene.exists() {
+            return Err(MapError::MissingScene(format!("{}.glb", map.file)));
+        }
+
+        Ok(map)
+    }
+
+    /// Parses a map from json, migrates it to the current format version, validates it
+    /// and loads its movement profile.
+    pub fn parse(contents: &str) -> Result<Self, MapError> {
+        // Read the version first, so maps from newer versions are reported as such
+        // instead of failing on fields we don't know about.
+        let value = serde_json::from_str::<serde_json::Value>(contents)?;
+        let version = match value.get("format_version") {
+            None => 0,
+            Some(version) => version
+                .as_u64()
+                .and_then(|version| u32::try_from(version).ok())
+                .ok_or_else(|| MapError::OutOfRange {
+                    field: "format_version".into(),
+                    reason: "must be a whole number that fits in 32 bits",
+                })?,
+        };
+        if version > MAP_FORMAT_VERSION {
+            return Err(MapError::UnsupportedVersion(version));
+        }
+        // Older builds ignore these fields, a map using them must say it needs a newer one.
+        if version < 2 {
+            if let Some(field) = VERSION_2_FIELDS.iter().find(|f| value.get(**f).is_some()) {
+                return Err(MapError::OutOfRange {
+                    field: field.to_string(),
+                    reason: "needs `format_version` 2 or later",
+                });
+            }
+        }
+
+        let mut map = serde_json::from_str::<Map>(contents)?;
+        map.migrate();
+        map.validate()?;
+        map.movement = MovementProfile::load(&map.profile).map_err(|error| MapError::Profile {
+            name: map.profile.clone(),
+            error,
+        })?;
+        Ok(map)
+    }
+
+    /// Upgrades a map read from an older format version to [`MAP_FORMAT_VERSION`].
+    fn migrate(&mut self) {
+        // 0 -> 1: Untagged maps. The schema is unchanged, they only lack the version.
+        if self.format_version == 0 {
+            self.format_version = 1;
+        }
+        // 1 -> 2: Ordered checkpoints, laps and profiles. Older maps have none of them,
+        // their defaults play like before.
+        if self.format_version == 1 {
+            self.format_version = 2;
+        }
+    }
+
+    fn validate(&self) -> Result<(), MapError> {
+        fn finite_vec(field: &str, v: Vec3) -> Result<(), MapError> {
+            if v.is_finite() {
+                Ok(())
+            } else {
+                Err(MapError::OutOfRange {
+                    field: field.into(),
+                    reason: "must be a finite position",
+                })
+            }
+        }
+
+        fn finite_angle(field: &str, angle: f32) -> Result<(), MapError> {
+            if angle.is_finite() {
+                Ok(())
+            } else {
+                Err(MapError::OutOfRange {
+                    field: field.into(),
+                    reason: "must be a finite angle in degrees",
+                })
+            }
+        }
+
+        if self.file.is_empty() {
+            return Err(MapError::OutOfRange {
+                field: "file".into(),
+                reason: "must not be empty",
+            });
+        }
+
+        if self.laps == 0 {
+            return Err(MapError::OutOfRange {
+                field: "laps".into(),
+                reason: "must be at least 1",
+            });
+        }
+
+        finite_vec("start_pos", self.start_pos)?;
+        finite_vec("end_pos", self.end_pos)?;
+        finite_angle("start_rotation", self.start_rotation)?;
+        finite_angle("end_rotation", self.end_rotation)?;
+
+        for (i, checkpoint) in self.checkpoints.iter().enumerate() {
+            finite_vec(&format!("checkpoints[{i}].pos"), checkpoint.pos)?;
+            finite_angle(&format!("checkpoints[{i}].rot"), checkpoint.rot)?;
+        }
+
+        for (i, pad) in self.pads.iter().flatten().enumerate() {
+            finite_vec(&format!("pads[{i}].pos"), pad.pos)?;
+            if !pad.strength.is_finite() || pad.strength <= 0. {
+                return Err(MapError::OutOfRange {
+                    field: format!("pads[{i}].strength"),
+                    reason: "must be a positive number",
+                });
+            }
+        }
+
+        if let Some(collidertype) = self.collidertype {
+            if collidertype > 2 {
+                return Err(MapError::OutOfRange {
+                    field: "collidertype".into(),
+                    reason: "must be 0, 1 or 2",
+                });
+            }
+        }
+
+        Ok(())
     }
 
     pub fn collider_type(&self) -> ComputedCollider {
@@ -76,23 +304,63 @@ impl Map {
     }
 }
 
+/// Returns the names of all maps that load, and the maps that don't together with the reason.
 #[cfg(target_arch = "wasm32")]
-pub fn all_maps() -> Vec<String> {
-    STATIC_MAPS.iter().map(|m| m.0.to_string()).collect()
+pub fn all_maps() -> (Vec<String>, Vec<(String, MapError)>) {
+    split_loadable(STATIC_MAPS.iter().map(|m| m.0.to_string()).collect())
 }
 
+/// Returns the names of all maps that load, and the maps that don't together with the reason.
 #[cfg(not(target_arch = "wasm32"))]
-pub fn all_maps() -> Vec<String> {
+pub fn all_maps() -> (Vec<String>, Vec<(String, MapError)>) {
     let path = Path::new("maps");
-    let dir = path.read_dir().unwrap();
-
-    dir.map(|f| {
-        println!("{:?}", f.as_ref().unwrap());
-        // I love you rust <3
-        f.unwrap().file_name().to_str().unwrap().into()
-    })
-    .filter(|m: &String| !m.ends_with("replay"))
-    .collect()
+    let dir = match path.read_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            println!("Could not read maps directory: {e}");
+            return (vec![], vec![]);
+        }
+    };
+
+    let mut names: Vec<String> = dir
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        // Maps have no extension, everything else (replays, highscores) lives next to them.
+        .filter(|p| p.is_file() && p.extension().is_none())
+        .filter_map(|p| p.file_name()?.to_str().map(String::from))
+        .collect();
+    names.sort();
+
+    split_loadable(names)
+}
+
+fn split_loadable(names: Vec<String>) -> (Vec<String>, Vec<(String, MapError)>) {
+    let mut maps = vec![];
+    let mut broken = vec![];
+
+    for name in names {
+        match Map::load(&name) {
+            Ok(_) => maps.push(name),
+            Err(e) => {
+                println!("Skipping map {name}: {e}");
+                broken.push((name, e));
+            }
+        }
+    }
+
+    (maps, broken)
+}
+
+/// Offset of the map scene from the map's coordinates.
+pub const MAP_OFFSET: Vec3 = Vec3::new(0., -3., 0.);
+
+/// Asset path of the scene in the map's glTF file.
+pub fn scene_path(map: &Map) -> String {
+    format!(
+        "{}.glb#{}",
+        map.file,
+        map.scene.as_deref().unwrap_or("Scene0")
+    )
 }
 
 pub fn spawn_map(
@@ -100,17 +368,13 @@ pub fn spawn_map(
     map: &Res<'_, Map>,
     commands: &mut Commands<'_, '_>,
 ) {
-    let map_data = if let Some(scene) = &map.scene {
-        assetserver.load(format!("{}.glb#{}", map.file, scene))
-    } else {
-        assetserver.load(format!("{}.glb#Scene0", map.file))
-    };
+    let map_data = assetserver.load(scene_path(map));
     commands.spawn((
         Name::new("Map"),
         AsyncSceneCollider::new(Some(ComputedCollider::TriMesh)),
         RigidBody::Static,
         SceneBundle {
-            transform: Transform::from_translation(Vec3::new(0., -3., 0.)),
+            transform: Transform::from_translation(MAP_OFFSET),
             scene: map_data,
             ..Default::default()
         },
@@ -121,10 +385,11 @@ pub fn spawn_map(
 
 pub fn add_collision_layers(
     mut commands: Commands,
-    query: Query<(Entity, Option<&Name>, &Parent), Added<Collider>>,
+    query: Query<(Entity, Option<&Name>, Option<&GltfExtras>, &Parent), Added<Collider>>,
+    nodes: Query<(Option<&Name>, Option<&GltfExtras>)>,
     checkpoints: Query<Has<checkpoint::Checkpoint>>,
 ) {
-    for (e, name, parent) in query.iter() {
+    for (e, name, extras, parent) in query.iter() {
         if checkpoints.get(parent.get()).is_ok() {
             continue;
         }
@@ -132,8 +397,86 @@ pub fn add_collision_layers(
             println!("Inserting collider for {name}");
             commands.entity(e).insert(CollisionLayers::new(
                 [PhysicsLayers::Ground],
-                [PhysicsLayers::Player],
+                [PhysicsLayers::Player, PhysicsLayers::Ghost],
             ));
         }
+
+        // Mesh entities are children of their glTF node, either can be tagged.
+        let surface = Surface::of(name, extras).or_else(|| {
+            let (name, extras) = nodes.get(parent.get()).ok()?;
+            Surface::of(name, extras)
+        });
+        if let Some(surface) = surface {
+            commands.entity(e).insert(surface);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = r#"{
+        "name": "test",
+        "file": "winter",
+        "start_pos": [0, 0, 0],
+        "end_pos": [0, 0, 10],
+        "end_rotation": 0,
+        "start_rotation": 0,
+        "checkpoints": [{ "pos": [0, 0, 5], "rot": 0 }],
+        "pads": null
+    }"#;
+
+    fn with_field(field: &str) -> String {
+        MAP.replacen('{', &format!("{{ {field},"), 1)
+    }
+
+    #[test]
+    fn future_version_is_rejected() {
+        let json = with_field(&format!(r#""format_version": {}"#, MAP_FORMAT_VERSION + 1));
+        assert!(matches!(
+            Map::parse(&json),
+            Err(MapError::UnsupportedVersion(v)) if v == MAP_FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn version_must_fit_in_32_bits() {
+        // Would wrap around to 1 if truncated.
+        for version in ["4294967297", "1.5", "-1", "\"1\""] {
+            let json = with_field(&format!(r#""format_version": {version}"#));
+            assert!(
+                matches!(Map::parse(&json), Err(MapError::OutOfRange { .. })),
+                "{version}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_version_is_migrated() {
+        let map = Map::parse(MAP).unwrap();
+        assert_eq!(map.format_version, MAP_FORMAT_VERSION);
+    }
+
+    #[test]
+    fn new_fields_need_a_new_version() {
+        let circuit = with_field(r#""laps": 3"#);
+        assert!(matches!(
+            Map::parse(&circuit),
+            Err(MapError::OutOfRange { field, .. }) if field == "laps"
+        ));
+
+        let circuit = with_field(r#""format_version": 2, "laps": 3"#);
+        assert_eq!(Map::parse(&circuit).unwrap().laps, 3);
+    }
+
+    #[test]
+    fn bad_checkpoint_is_rejected() {
+        // Too large for an f32, it's read as infinity.
+        let json = MAP.replace(r#""rot": 0 }"#, r#""rot": 1e300 }"#);
+        let Err(MapError::OutOfRange { field, .. }) = Map::parse(&json) else {
+            panic!("A checkpoint with an infinite rotation must be rejected.");
+        };
+        assert_eq!(field, "checkpoints[0].rot");
     }
 }
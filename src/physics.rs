@@ -1,8 +1,83 @@
-use bevy_xpbd_3d::prelude::PhysicsLayer;
+use bevy::{prelude::*, transform::TransformSystem};
+use bevy_xpbd_3d::{
+    prelude::{PhysicsLayer, Position},
+    PhysicsSet,
+};
+
+/// Simulation ticks per second. Physics and the character controller step once per tick.
+pub const TICK_RATE: f64 = 60.;
 
 #[derive(PhysicsLayer)]
 pub enum PhysicsLayers {
     Player,
     Sensor,
     Ground,
+    Ghost,
+}
+
+pub struct InterpolationPlugin;
+
+impl Plugin for InterpolationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(FixedUpdate, store_tick_position.after(PhysicsSet::Sync))
+            .add_systems(
+                PostUpdate,
+                interpolate_models.before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+/// Position of a body at the previous and the latest simulation tick.
+/// Rendering happens in between ticks, so the visible position is interpolated between both.
+#[derive(Component)]
+pub struct InterpolatedPosition {
+    previous: Vec3,
+    current: Vec3,
+    /// The interpolated position for the current frame.
+    pub rendered: Vec3,
+}
+
+impl InterpolatedPosition {
+    pub fn new(position: Vec3) -> Self {
+        Self {
+            previous: position,
+            current: position,
+            rendered: position,
+        }
+    }
+
+    /// Position at a point during the latest tick, `fraction` 0 is its start and 1 its end.
+    pub fn during_tick(&self, fraction: f32) -> Vec3 {
+        self.previous.lerp(self.current, fraction)
+    }
+}
+
+/// Marker for the visible child of a body with [`InterpolatedPosition`].
+/// Its translation is offset so it's drawn at the interpolated position.
+#[derive(Component)]
+pub struct InterpolatedModel;
+
+pub fn store_tick_position(mut query: Query<(&Position, &mut InterpolatedPosition)>) {
+    for (position, mut interpolated) in &mut query {
+        interpolated.previous = interpolated.current;
+        interpolated.current = position.0;
+    }
+}
+
+pub fn interpolate_models(
+    time: Res<Time<Fixed>>,
+    mut bodies: Query<(&mut InterpolatedPosition, &Children)>,
+    mut models: Query<&mut Transform, With<InterpolatedModel>>,
+) {
+    let alpha = time.overstep_percentage();
+
+    for (mut interpolated, children) in &mut bodies {
+        interpolated.rendered = interpolated.previous.lerp(interpolated.current, alpha);
+
+        for child in children {
+            if let Ok(mut transform) = models.get_mut(*child) {
+                transform.translation = interpolated.rendered - interpolated.current;
+            }
+        }
+    }
 }
@@ -1,53 +1,110 @@
 use std::{f32::consts::PI, time::Duration};
 
-use bevy::prelude::*;
-use bevy_xpbd_3d::{
-    math::Scalar,
-    prelude::{Collider, LinearVelocity, RayCaster},
-};
+use bevy::{prelude::*, utils::HashMap};
+use bevy_xpbd_3d::prelude::{Collider, CollisionLayers, RayCaster, SpatialQueryFilter};
 
 use crate::{
     assets::{Animations, AssetHandles},
     camera::{CameraLeash, LeashedCamera},
-    character_controller::{CharacterControllerBundle, Grounded, JumpCount, Sliding},
-    ghost::{Ghost, GhostData},
+    character_controller::{AnimationState, CharacterControllerBundle, GroundState},
+    checkpoint::CheckpointProgress,
+    ghost::GhostDelay,
     input::ResetSnapshot,
     map::Map,
+    physics::{InterpolatedModel, InterpolatedPosition, PhysicsLayers},
+    profile::MovementProfile,
+    replay::{Replay, ReplayRecorder},
     MapEntityMarker, Player,
 };
 
-pub fn spawn_player(map: &Res<Map>, commands: &mut Commands, asset_handles: &Res<AssetHandles>) {
-    let mut player_transform = Transform::from_translation(map.start_pos);
-    player_transform.translation.y -= 1.;
+/// Marker for the fox model, which is a child of the [`Player`] physics body.
+#[derive(Component)]
+pub struct PlayerModel;
+
+pub fn start_transform(map: &Map) -> Transform {
+    let mut transform = Transform::from_translation(map.start_pos);
+    transform.translation.y -= 1.;
+    transform
+}
+
+/// Resetting before reaching a checkpoint returns to the start.
+pub fn start_snapshot(map: &Map) -> ResetSnapshot {
+    ResetSnapshot {
+        pos: start_transform(map).translation,
+        camera: (map.start_rotation.to_radians(), -0.2),
+        ..Default::default()
+    }
+}
+
+/// The character controller of the fox, moving with the given profile.
+pub fn fox_controller(profile: &MovementProfile) -> CharacterControllerBundle {
+    CharacterControllerBundle::new(
+        Collider::compound(vec![(
+            Vec3::new(0., 1.5, 0.),
+            Quat::default(),
+            Collider::ball(1.5),
+        )]),
+        profile,
+    )
+}
 
-    commands.spawn((
+/// The simulated player body, without the model.
+pub fn player_bundle(map: &Map) -> impl Bundle {
+    let player_transform = start_transform(map);
+
+    (
         Name::new("Player"),
-        SceneBundle {
-            transform: player_transform,
-            scene: asset_handles.fox.clone(),
-            ..Default::default()
-        },
+        SpatialBundle::from_transform(player_transform),
+        InterpolatedPosition::new(player_transform.translation),
         CameraLeash,
-        CharacterControllerBundle::new(
-            Collider::compound(vec![(
-                Vec3::new(0., 1.5, 0.),
-                Quat::default(),
-                Collider::ball(1.5),
-            )]),
-            Vec3::NEG_Y * 9.81 * 2.0,
-        )
-        .with_movement(30.0, 0.98, 10.0, (15.0 as Scalar).to_radians()),
-        GhostData::default(),
+        fox_controller(&map.movement),
+        CollisionLayers::new(
+            [PhysicsLayers::Player],
+            [PhysicsLayers::Ground, PhysicsLayers::Sensor],
+        ),
+        ReplayRecorder(Replay::new(map)),
         Player,
         MapEntityMarker,
-        ResetSnapshot::default(),
-        RayCaster::new(Vec3::new(0., 1., 0.), Vec3::ZERO).with_max_hits(1),
-    ));
+        start_snapshot(map),
+        GhostDelay::default(),
+        CheckpointProgress::default(),
+        camera_ray(),
+    )
+}
+
+/// Pulls the camera in front of walls between it and the [`CameraLeash`].
+pub fn camera_ray() -> RayCaster {
+    RayCaster::new(Vec3::new(0., 1., 0.), Vec3::ZERO)
+        .with_max_hits(1)
+        .with_query_filter(SpatialQueryFilter::new().with_masks([PhysicsLayers::Ground]))
+}
+
+pub fn spawn_player(
+    map: &Res<Map>,
+    commands: &mut Commands,
+    asset_handles: &Res<AssetHandles>,
+) -> Entity {
+    commands
+        .spawn(player_bundle(map))
+        .with_children(|parent| {
+            // The model is a separate entity, so it can be interpolated and rotated
+            // without touching the simulated body.
+            parent.spawn((
+                Name::new("Player model"),
+                SceneBundle {
+                    scene: asset_handles.fox.clone(),
+                    ..Default::default()
+                },
+                PlayerModel,
+                InterpolatedModel,
+            ));
+        })
+        .id()
 }
 
 pub fn rotate_player_model(
-    mut query: Query<&mut Transform, With<Player>>,
-    cameras: Query<&Transform, (With<LeashedCamera>, Without<Player>)>,
+    mut query: Query<&mut Transform, With<PlayerModel>>,
+    cameras: Query<&Transform, (With<LeashedCamera>, Without<PlayerModel>)>,
 ) {
     let camera_transform = cameras.single();
 
@@ -68,36 +125,28 @@ pub fn rotate_player_model(
     }
 }
 
-#[allow(clippy::type_complexity)]
-pub fn update_player_animation(
-    mut query: Query<
-        (
-            Entity,
-            &LinearVelocity,
-            Has<Grounded>,
-            Has<Sliding>,
-            &JumpCount,
-        ),
-        With<Player>,
-    >,
+/// Plays the clip of the [`AnimationState`] of the player and of ghosts.
+pub fn update_animations(
+    query: Query<(Entity, &AnimationState)>,
     mut animation_player: Query<&mut AnimationPlayer>,
     animations: Res<Animations>,
     children: Query<&Children>,
-    mut jc: Local<i32>,
+    // The jump count whose clip is playing, per airborne entity.
+    mut jumps: Local<HashMap<Entity, u32>>,
 ) {
-    for (e, linear_velocity, is_grounded, is_sliding, jump_count) in &mut query {
+    for (e, state) in &query {
         for entity in children.iter_descendants(e) {
             if let Ok(mut animation_player) = animation_player.get_mut(entity) {
-                if linear_velocity.0.length() > 1. && (is_sliding || is_grounded) {
+                if state.moving && state.ground != GroundState::Airborne {
                     animation_player
                         .play_with_transition(
                             animations.0[1].clone_weak(),
                             Duration::from_millis(100),
                         )
                         .repeat();
-                    *jc = -1;
-                } else if !is_grounded && !is_sliding {
-                    if *jc != jump_count.0 as i32 {
+                    jumps.remove(&e);
+                } else if state.ground == GroundState::Airborne {
+                    if jumps.get(&e) != Some(&state.jump_count) {
                         if animation_player.is_playing_clip(&animations.0[2]) {
                             animation_player.replay();
                         } else {
@@ -106,7 +155,7 @@ pub fn update_player_animation(
                                 Duration::from_millis(50),
                             );
                         }
-                        *jc = jump_count.0 as i32;
+                        jumps.insert(e, state.jump_count);
                     }
                 } else {
                     animation_player
@@ -115,7 +164,7 @@ pub fn update_player_animation(
                             Duration::from_millis(100),
                         )
                         .repeat();
-                    *jc = -1;
+                    jumps.remove(&e);
                 }
             }
         }
@@ -0,0 +1,214 @@
+//! Movement profiles: the tunables of the character controller, loaded from `profiles/{name}.json`.
+//!
+//! A map names the profile it is driven with. Times are only comparable with the same profile,
+//! so the leaderboard keeps them apart.
+
+use std::fmt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::replay::fnv1a;
+
+/// Profile of maps that don't name one.
+pub const DEFAULT_PROFILE: &str = "default";
+
+pub fn default_profile() -> String {
+    DEFAULT_PROFILE.into()
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MovementProfile {
+    /// Horizontal acceleration from movement input.
+    pub acceleration: f32,
+    /// Horizontal velocity kept per tick at 60 ticks per second on the ground.
+    pub damping: f32,
+    /// Horizontal velocity kept per tick at 60 ticks per second in the air and while sliding.
+    pub air_damping: f32,
+    /// How fast air strafing reaches [`MovementProfile::air_wish_speed`], in multiples of it per second.
+    pub air_acceleration: f32,
+    /// In the air, input only accelerates up to this speed along its own direction.
+    /// Strafing sideways to the velocity still gains speed, turning with it gains more.
+    pub air_wish_speed: f32,
+    /// Horizontal speed above which the excess decays with [`MovementProfile::over_cap_decay`].
+    pub speed_soft_cap: f32,
+    /// Part of the speed above the soft cap kept per tick at 60 ticks per second.
+    pub over_cap_decay: f32,
+    /// Vertical velocity of a jump.
+    pub jump_impulse: f32,
+    /// Steepest walkable slope in degrees, steeper ones are slid down.
+    pub max_slope_angle: f32,
+    /// Downwards acceleration.
+    pub gravity: f32,
+    /// Added to the acceleration multiplier by every jump off the ground.
+    pub jump_multiplier_bonus: f32,
+    /// Jumps possible in the air before landing again.
+    pub air_jumps: u32,
+    /// Acceleration multiplier kept per tick at 60 ticks per second on the ground.
+    pub ground_multiplier_decay: f32,
+    /// Acceleration multiplier kept per tick at 60 ticks per second in the air.
+    pub air_multiplier_decay: f32,
+    pub max_multiplier: f32,
+    /// Time after a jump before jumping off the ground again, in milliseconds.
+    pub jump_cooldown_ms: u64,
+}
+
+impl Default for MovementProfile {
+    fn default() -> Self {
+        Self {
+            acceleration: 30.,
+            damping: 0.98,
+            air_damping: 0.995,
+            air_acceleration: 100.,
+            air_wish_speed: 3.,
+            speed_soft_cap: 60.,
+            over_cap_decay: 0.95,
+            jump_impulse: 10.,
+            max_slope_angle: 15.,
+            gravity: 19.62,
+            jump_multiplier_bonus: 1.1,
+            air_jumps: 2,
+            ground_multiplier_decay: 0.97,
+            air_multiplier_decay: 0.999,
+            max_multiplier: 10.,
+            jump_cooldown_ms: 300,
+        }
+    }
+}
+
+/// Reasons a movement profile can fail to load.
+#[derive(Debug)]
+pub enum ProfileError {
+    /// There is no profile with this name.
+    Missing,
+    #[cfg(not(target_arch = "wasm32"))]
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// A field holds a value the controller can't use.
+    OutOfRange {
+        field: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileError::Missing => write!(f, "profile doesn't exist"),
+            #[cfg(not(target_arch = "wasm32"))]
+            ProfileError::Io(e) => write!(f, "profile could not be read: {e}"),
+            ProfileError::Json(e) => write!(f, "invalid profile json: {e}"),
+            ProfileError::OutOfRange { field, reason } => write!(f, "`{field}` {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+/// Path of a profile in the content directory, `None` if the name isn't a valid file name.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn profile_path(name: &str) -> Option<PathBuf> {
+    // Profiles are named by maps, which must not reach outside the profiles directory.
+    if name.is_empty() || name.contains(['/', '\\', '.']) {
+        return None;
+    }
+    Some(Path::new("profiles").join(format!("{name}.json")))
+}
+
+#[cfg(target_arch = "wasm32")]
+const STATIC_PROFILES: [(&str, &str); 1] =
+    [(DEFAULT_PROFILE, include_str!("../profiles/default.json"))];
+
+impl MovementProfile {
+    #[cfg(target_arch = "wasm32")]
+    pub fn load(name: &str) -> Result<Self, ProfileError> {
+        let (_, contents) = STATIC_PROFILES
+            .iter()
+            .find(|p| p.0 == name)
+            .ok_or(ProfileError::Missing)?;
+        Self::parse(contents)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load(name: &str) -> Result<Self, ProfileError> {
+        let path = profile_path(name).ok_or(ProfileError::Missing)?;
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(ProfileError::Missing),
+            Err(e) => Err(ProfileError::Io(e)),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, ProfileError> {
+        let profile: Self = serde_json::from_str(contents).map_err(ProfileError::Json)?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    fn validate(&self) -> Result<(), ProfileError> {
+        fn positive(field: &'static str, value: f32) -> Result<(), ProfileError> {
+            if value.is_finite() && value > 0. {
+                Ok(())
+            } else {
+                Err(ProfileError::OutOfRange {
+                    field,
+                    reason: "must be a positive number",
+                })
+            }
+        }
+
+        fn factor(field: &'static str, value: f32) -> Result<(), ProfileError> {
+            if (0. ..=1.).contains(&value) {
+                Ok(())
+            } else {
+                Err(ProfileError::OutOfRange {
+                    field,
+                    reason: "must be between 0 and 1",
+                })
+            }
+        }
+
+        positive("acceleration", self.acceleration)?;
+        factor("damping", self.damping)?;
+        factor("air_damping", self.air_damping)?;
+        positive("air_acceleration", self.air_acceleration)?;
+        positive("air_wish_speed", self.air_wish_speed)?;
+        positive("speed_soft_cap", self.speed_soft_cap)?;
+        factor("over_cap_decay", self.over_cap_decay)?;
+        positive("jump_impulse", self.jump_impulse)?;
+        if !(0. ..=90.).contains(&self.max_slope_angle) {
+            return Err(ProfileError::OutOfRange {
+                field: "max_slope_angle",
+                reason: "must be between 0 and 90 degrees",
+            });
+        }
+        positive("gravity", self.gravity)?;
+        if !self.jump_multiplier_bonus.is_finite() || self.jump_multiplier_bonus < 0. {
+            return Err(ProfileError::OutOfRange {
+                field: "jump_multiplier_bonus",
+                reason: "must not be negative",
+            });
+        }
+        factor("ground_multiplier_decay", self.ground_multiplier_decay)?;
+        factor("air_multiplier_decay", self.air_multiplier_decay)?;
+        if !self.max_multiplier.is_finite() || self.max_multiplier < 1. {
+            return Err(ProfileError::OutOfRange {
+                field: "max_multiplier",
+                reason: "must be at least 1",
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Identifies the tunables. Runs are only comparable with the same hash.
+    pub fn hash(&self) -> u64 {
+        let json = serde_json::to_vec(self).expect("Profiles always serialize.");
+        fnv1a(&json)
+    }
+}
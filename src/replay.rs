@@ -0,0 +1,361 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use bevy_xpbd_3d::{prelude::Position, PhysicsSet};
+use serde::Deserialize;
+
+use crate::{
+    character_controller::{AnimationState, ControllerSet, TickInput},
+    map::Map,
+    physics::{InterpolatedModel, InterpolatedPosition, TICK_RATE},
+    profile::default_profile,
+    replay_format::{self, DecodeError},
+    storage::{self, StorageError},
+    timing::MapDuration,
+    Player,
+};
+
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            (
+                feed_replay_input.in_set(ControllerSet::Input),
+                record_replay
+                    .after(ControllerSet::Input)
+                    .before(ControllerSet::Movement),
+                record_track.after(PhysicsSet::Sync),
+            )
+                .run_if(in_state(crate::State::Playing)),
+        )
+        .add_systems(Update, follow_tracks);
+    }
+}
+
+/// Version of the replay format this build reads and writes.
+/// Version 1 replays were json without a track, newer ones use [`replay_format`].
+/// Version 2 didn't store the finish time yet.
+pub const REPLAY_FORMAT_VERSION: u32 = 3;
+
+/// Version of the simulation: the character controller, physics and everything else that moves
+/// the player. Bump it with every change to them, replays of another version run differently.
+pub const SIMULATION_VERSION: u32 = 3;
+
+/// Identifies the release and simulation a replay was recorded with.
+/// A replay only re-simulates exactly with the same ones.
+pub const GAME_VERSION_HASH: u64 = fnv1a_extend(
+    fnv1a(concat!(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")).as_bytes()),
+    &SIMULATION_VERSION.to_le_bytes(),
+);
+
+pub const fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_extend(0xcbf29ce484222325, bytes)
+}
+
+/// Continues an [`fnv1a`] hash with more bytes.
+const fn fnv1a_extend(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    hash
+}
+
+/// The input of a run, one [`TickInput`] per simulation tick after the countdown.
+/// Feeding it back into a character controller rebuilds the run exactly.
+///
+/// Only deserialized from json to read replays of format version 1.
+#[derive(Deserialize, Clone)]
+pub struct Replay {
+    pub format_version: u32,
+    pub game_version: u64,
+    pub map: String,
+    /// The map's [`crate::profile::MovementProfile`] when the run was recorded.
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    /// [`crate::profile::MovementProfile::hash`] of that profile, 0 for replays that predate profiles.
+    /// Those were driven with older movement and can't be re-simulated anymore.
+    #[serde(default)]
+    pub profile_hash: u64,
+    pub tick_rate: f64,
+    pub ticks: Vec<TickInput>,
+    /// Where the player was at the end of every tick, to watch the run without re-simulating it.
+    #[serde(skip)]
+    pub track: Vec<TrackSample>,
+    /// Time of the run at the goal, in between the ticks around it.
+    /// `None` for unfinished runs and replays that predate it.
+    #[serde(skip)]
+    pub finish: Option<Duration>,
+}
+
+/// The recorded character at the end of a tick.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct TrackSample {
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub animation: AnimationState,
+}
+
+impl Replay {
+    pub fn new(map: &Map) -> Self {
+        Self {
+            format_version: REPLAY_FORMAT_VERSION,
+            game_version: GAME_VERSION_HASH,
+            map: map.name.clone(),
+            profile: map.profile.clone(),
+            profile_hash: map.movement.hash(),
+            tick_rate: TICK_RATE,
+            ticks: vec![],
+            track: vec![],
+            finish: None,
+        }
+    }
+
+    /// Duration of the recorded ticks.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.ticks.len() as f64 / self.tick_rate)
+    }
+
+    /// Time of the recorded run, the same as on the leaderboard. Replays without a finish time
+    /// fall back to the [`Self::duration`] of their ticks.
+    pub fn time(&self) -> Duration {
+        self.finish.unwrap_or_else(|| self.duration())
+    }
+
+    /// Whether the replay's track can be shown on the given map. Unlike re-simulating it,
+    /// that works with any build and profile.
+    pub fn is_watchable_on(&self, map: &Map) -> bool {
+        self.format_version == REPLAY_FORMAT_VERSION && self.map == map.name
+    }
+
+    /// The recorded character `time` after the start, interpolated between the ticks around it.
+    /// `None` if the replay has no track.
+    pub fn sample(&self, time: Duration) -> Option<TrackSample> {
+        // The sample of a tick is where the character was at its end.
+        let index = (time.as_secs_f64() * self.tick_rate - 1.).max(0.);
+        let before = (index.floor() as usize).min(self.track.len().checked_sub(1)?);
+        let after = (before + 1).min(self.track.len() - 1);
+        let (before, after) = (self.track[before], self.track[after]);
+        let fraction = index.fract() as f32;
+
+        Some(TrackSample {
+            position: before.position.lerp(after.position, fraction),
+            rotation: before.rotation.slerp(after.rotation, fraction),
+            animation: before.animation,
+        })
+    }
+
+    /// When the recorded character came closest to `position`, interpolated along the track.
+    /// On tracks that pass the same spot twice, the closer pass wins.
+    /// `None` if the replay has no track.
+    pub fn time_at(&self, position: Vec3) -> Option<Duration> {
+        let first = self.track.first()?;
+        let mut closest = (first.position.distance_squared(position), 0.);
+
+        for (i, pair) in self.track.windows(2).enumerate() {
+            let (start, end) = (pair[0].position, pair[1].position);
+            let segment = end - start;
+            let fraction = if segment == Vec3::ZERO {
+                0.
+            } else {
+                ((position - start).dot(segment) / segment.length_squared()).clamp(0., 1.)
+            };
+            let distance = (start + segment * fraction).distance_squared(position);
+            if distance < closest.0 {
+                closest = (distance, i as f64 + fraction as f64);
+            }
+        }
+
+        // The sample at index i is the end of tick i + 1, see `sample`.
+        Some(Duration::from_secs_f64((closest.1 + 1.) / self.tick_rate))
+    }
+
+    /// Whether this build can re-simulate the replay on the given map with its current profile.
+    pub fn is_playable_on(&self, map: &Map) -> bool {
+        self.format_version == REPLAY_FORMAT_VERSION
+            && self.game_version == GAME_VERSION_HASH
+            && self.tick_rate == TICK_RATE
+            && self.map == map.name
+            && self.profile == map.profile
+            && self.profile_hash == map.movement.hash()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        replay_format::encode(self)
+    }
+
+    /// Reads a replay in the binary format or a json one of format version 1.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.starts_with(replay_format::MAGIC) {
+            replay_format::decode(bytes)
+        } else {
+            serde_json::from_slice(bytes).map_err(|e| DecodeError::Invalid(e.to_string()))
+        }
+    }
+
+    /// Loads a replay from the user data directory. Returns `None` if it doesn't exist.
+    /// Replays of an unsupported version are an error, but left in place.
+    pub fn load(path: &Path) -> Result<Option<Self>, StorageError> {
+        // A replay of another version isn't corrupt, it's passed on instead of quarantined.
+        let decoded = storage::load(path, |bytes| match Self::decode(bytes) {
+            Err(DecodeError::Invalid(message)) => Err(message),
+            decoded => Ok(decoded),
+        })?;
+        decoded.transpose().map_err(|e| StorageError::Invalid {
+            path: path.to_owned(),
+            message: e.to_string(),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), StorageError> {
+        storage::write(path, &self.encode())
+    }
+}
+
+/// Path of the replay of the fastest local run on a map, in the user data directory.
+pub fn replay_path(map: &str) -> PathBuf {
+    Path::new("ghosts").join(format!("{map}.replay"))
+}
+
+/// Path of the replay of the most recent finished run on a map, in the user data directory.
+pub fn last_replay_path(map: &str) -> PathBuf {
+    Path::new("ghosts").join(format!("{map}.last.replay"))
+}
+
+/// Path of a replay imported for a map, in the user data directory.
+pub fn imported_replay_path(map: &str, name: &str) -> PathBuf {
+    Path::new("ghosts")
+        .join("imported")
+        .join(map)
+        .join(format!("{name}.replay"))
+}
+
+/// Where [`replay_path`] used to be, in the content directory.
+pub fn legacy_replay_path(map: &str) -> PathBuf {
+    Path::new("maps").join(format!("{map}.replay"))
+}
+
+/// Records the player's input every tick once the run started.
+#[derive(Component)]
+pub struct ReplayRecorder(pub Replay);
+
+/// Drives a character controller from a [`Replay`] instead of the keyboard.
+#[derive(Component)]
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    tick: usize,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        Self { replay, tick: 0 }
+    }
+
+    /// Plays `input` on the next tick. Ticks that already passed without input stay empty.
+    pub fn play_next(&mut self, input: TickInput) {
+        self.replay.ticks.resize(self.tick, TickInput::default());
+        self.replay.ticks.push(input);
+    }
+}
+
+fn record_replay(mut query: Query<(&TickInput, &mut ReplayRecorder), With<MapDuration>>) {
+    for (input, mut recorder) in &mut query {
+        recorder.0.ticks.push(input.clone());
+    }
+}
+
+fn record_track(
+    mut query: Query<
+        (&Position, &TickInput, &AnimationState, &mut ReplayRecorder),
+        With<MapDuration>,
+    >,
+) {
+    for (position, input, animation, mut recorder) in &mut query {
+        recorder.0.track.push(TrackSample {
+            position: position.0,
+            rotation: Quat::from_rotation_y(input.yaw),
+            animation: *animation,
+        });
+    }
+}
+
+/// Shows a recorded run by following the track of its [`Replay`] instead of simulating it,
+/// so it can be paused and seeked. The owner of the entity decides which `time` is shown.
+#[derive(Component)]
+pub struct TrackPlayback {
+    pub replay: Replay,
+    pub time: Duration,
+}
+
+impl TrackPlayback {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            replay,
+            time: Duration::ZERO,
+        }
+    }
+}
+
+/// Moves entities with a [`TrackPlayback`] to where the run was at its time.
+/// Their [`InterpolatedModel`] faces the recorded direction.
+pub fn follow_tracks(
+    mut query: Query<(
+        &TrackPlayback,
+        &mut Transform,
+        &mut AnimationState,
+        Option<&mut Position>,
+        Option<&mut InterpolatedPosition>,
+        Option<&Children>,
+    )>,
+    mut models: Query<&mut Transform, (With<InterpolatedModel>, Without<TrackPlayback>)>,
+) {
+    for (playback, mut transform, mut animation, position, interpolated, children) in &mut query {
+        let Some(sample) = playback.replay.sample(playback.time) else {
+            continue;
+        };
+
+        transform.translation = sample.position;
+        *animation = sample.animation;
+        // Ray casters, like the one of the camera leash, start at the position.
+        if let Some(mut position) = position {
+            position.0 = sample.position;
+        }
+        if let Some(mut interpolated) = interpolated {
+            // Already sampled in between ticks, there's nothing left to interpolate.
+            *interpolated = InterpolatedPosition::new(sample.position);
+        }
+        for child in children.into_iter().flatten() {
+            if let Ok(mut model) = models.get_mut(*child) {
+                model.rotation = sample.rotation;
+            }
+        }
+    }
+}
+
+/// Replays start on the same tick as the player's run.
+fn feed_replay_input(
+    started: Query<(), (With<Player>, With<MapDuration>)>,
+    mut query: Query<(&mut ReplayPlayback, &mut TickInput)>,
+) {
+    if started.is_empty() {
+        return;
+    }
+
+    for (mut playback, mut input) in &mut query {
+        // Past the end, the controller keeps simulating without input.
+        *input = playback
+            .replay
+            .ticks
+            .get(playback.tick)
+            .cloned()
+            .unwrap_or_default();
+        playback.tick += 1;
+    }
+}
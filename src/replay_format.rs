@@ -0,0 +1,402 @@
+//! The binary file format of [`Replay`]s.
+//!
+//! After a fixed size header, consecutive ticks with the same input share one run length encoded
+//! entry. The track stores positions quantised to [`POSITION_STEPS`] per metre as the difference
+//! to the previous tick, rotations as their three smallest components and the animation state
+//! packed into a byte. Integers after the header are LEB128 varints, signed ones zigzag encoded,
+//! so a tick of steady movement takes a few bytes.
+//!
+//! Version 2 files lack the finish time in the header and are read as unfinished.
+
+use std::{fmt, time::Duration};
+
+use bevy::prelude::*;
+
+use crate::{
+    character_controller::{AnimationState, GroundState, MovementAction, TickInput},
+    replay::{Replay, TrackSample, REPLAY_FORMAT_VERSION},
+};
+
+/// Start of every binary replay file.
+pub const MAGIC: &[u8; 4] = b"GGFR";
+
+/// Quantisation steps per metre of track positions.
+const POSITION_STEPS: f32 = 1024.;
+
+/// Bits of each of the three smallest quaternion components.
+const ROTATION_BITS: u32 = 10;
+
+/// The three smaller components of a unit quaternion are at most 1/sqrt(2).
+const SMALLEST_THREE_MAX: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// More ticks than this are rejected instead of allocated, it's over a day at 60 ticks per second.
+const MAX_TICKS: u64 = 10_000_000;
+
+/// Stored as the finish time of runs without one.
+const NO_FINISH: u64 = u64::MAX;
+
+/// Jump counts above this are stored as this, it's only used to restart the jump clip.
+const MAX_JUMP_COUNT: u32 = 31;
+
+/// Reasons a replay can't be decoded.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// A replay of another format version. It isn't corrupt, this build just can't read it.
+    UnsupportedVersion(u32),
+    Invalid(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported replay format version {version}")
+            }
+            DecodeError::Invalid(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl From<String> for DecodeError {
+    fn from(message: String) -> Self {
+        DecodeError::Invalid(message)
+    }
+}
+
+impl From<&str> for DecodeError {
+    fn from(message: &str) -> Self {
+        DecodeError::Invalid(message.into())
+    }
+}
+
+pub fn encode(replay: &Replay) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.u32(replay.format_version);
+    writer.u64(replay.game_version);
+    writer.string(&replay.map);
+    writer.string(&replay.profile);
+    writer.u64(replay.profile_hash);
+    writer.u64(replay.tick_rate.to_bits());
+    let finish = replay
+        .finish
+        .map_or(NO_FINISH, |finish| finish.as_nanos() as u64);
+    writer.u64(finish);
+
+    // Inputs are compared by their encoding, so runs never merge inputs that only compare equal,
+    // like a yaw of 0 and -0.
+    let mut runs: Vec<(Vec<u8>, u64)> = vec![];
+    for input in &replay.ticks {
+        let encoded = encode_input(input);
+        match runs.last_mut() {
+            Some((last, count)) if *last == encoded => *count += 1,
+            _ => runs.push((encoded, 1)),
+        }
+    }
+    writer.varint(runs.len() as u64);
+    for (input, count) in runs {
+        writer.varint(count);
+        writer.bytes.extend_from_slice(&input);
+    }
+
+    writer.varint(replay.track.len() as u64);
+    let mut previous_position = IVec3::ZERO;
+    let mut previous_rotation = None;
+    for sample in &replay.track {
+        let position = quantise_position(sample.position);
+        writer.zigzag(position.x.wrapping_sub(previous_position.x));
+        writer.zigzag(position.y.wrapping_sub(previous_position.y));
+        writer.zigzag(position.z.wrapping_sub(previous_position.z));
+        previous_position = position;
+
+        // The rotation only changes with the camera, 0 repeats the previous one.
+        let rotation = pack_rotation(sample.rotation);
+        if previous_rotation == Some(rotation) {
+            writer.varint(0);
+        } else {
+            writer.varint(rotation as u64 + 1);
+            previous_rotation = Some(rotation);
+        }
+
+        writer.bytes.push(pack_animation(sample.animation));
+    }
+
+    writer.bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Replay, DecodeError> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("not a replay file".into());
+    }
+
+    let format_version = reader.u32()?;
+    if !(2..=REPLAY_FORMAT_VERSION).contains(&format_version) {
+        return Err(DecodeError::UnsupportedVersion(format_version));
+    }
+    let game_version = reader.u64()?;
+    let map = reader.string()?;
+    let profile = reader.string()?;
+    let profile_hash = reader.u64()?;
+    let tick_rate = f64::from_bits(reader.u64()?);
+    let finish = match format_version {
+        2 => None,
+        _ => Some(reader.u64()?)
+            .filter(|&nanos| nanos != NO_FINISH)
+            .map(Duration::from_nanos),
+    };
+
+    let mut ticks = vec![];
+    for _ in 0..reader.length()? {
+        let count = reader.varint()?;
+        let input = reader.input()?;
+        if ticks.len() as u64 + count > MAX_TICKS {
+            return Err("too many ticks".into());
+        }
+        ticks.extend(std::iter::repeat(input).take(count as usize));
+    }
+
+    let mut track = vec![];
+    let mut position = IVec3::ZERO;
+    let mut rotation = Quat::IDENTITY;
+    for _ in 0..reader.length()? {
+        position.x = position.x.wrapping_add(reader.zigzag()?);
+        position.y = position.y.wrapping_add(reader.zigzag()?);
+        position.z = position.z.wrapping_add(reader.zigzag()?);
+
+        match reader.varint()? {
+            0 => {}
+            packed => {
+                let packed = u32::try_from(packed - 1).map_err(|_| "invalid rotation")?;
+                rotation = unpack_rotation(packed);
+            }
+        }
+
+        track.push(TrackSample {
+            position: position.as_vec3() / POSITION_STEPS,
+            rotation,
+            animation: unpack_animation(reader.u8()?)?,
+        });
+    }
+
+    if reader.remaining() > 0 {
+        return Err("unexpected data after the track".into());
+    }
+    // Every tick ends with a sample of the track.
+    if track.len() != ticks.len() {
+        return Err(format!("{} ticks but {} track samples", ticks.len(), track.len()).into());
+    }
+
+    Ok(Replay {
+        // Older versions are read into the current one.
+        format_version: REPLAY_FORMAT_VERSION,
+        game_version,
+        map,
+        profile,
+        profile_hash,
+        tick_rate,
+        ticks,
+        track,
+        finish,
+    })
+}
+
+fn encode_input(input: &TickInput) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.u32(input.yaw.to_bits());
+    writer.varint(input.actions.len() as u64);
+    for action in &input.actions {
+        match action {
+            MovementAction::Move(direction) => {
+                writer.bytes.push(0);
+                for component in direction.to_array() {
+                    writer.u32(component.to_bits());
+                }
+            }
+            MovementAction::Jump => writer.bytes.push(1),
+            MovementAction::Reset => writer.bytes.push(2),
+        }
+    }
+    writer.bytes
+}
+
+fn quantise_position(position: Vec3) -> IVec3 {
+    (position * POSITION_STEPS).round().as_ivec3()
+}
+
+/// Drops the largest component of the quaternion and stores its index in the top two bits.
+/// It is restored from the others, as the quaternion has unit length.
+fn pack_rotation(rotation: Quat) -> u32 {
+    let components = rotation.normalize().to_array();
+    let largest = (0..4)
+        .max_by(|&a, &b| components[a].abs().total_cmp(&components[b].abs()))
+        .expect("Quaternions have four components.");
+    // `q` and `-q` are the same rotation, pick the one with a positive largest component.
+    let sign = components[largest].signum();
+    let max = ((1 << ROTATION_BITS) - 1) as f32;
+
+    let mut packed = largest as u32;
+    for (_, component) in components.iter().enumerate().filter(|(i, _)| *i != largest) {
+        let unit = (component * sign / SMALLEST_THREE_MAX * 0.5 + 0.5).clamp(0., 1.);
+        packed = (packed << ROTATION_BITS) | (unit * max).round() as u32;
+    }
+    packed
+}
+
+fn unpack_rotation(packed: u32) -> Quat {
+    let mask = (1 << ROTATION_BITS) - 1;
+    let largest = (packed >> (3 * ROTATION_BITS)) as usize & 3;
+
+    let mut components = [0.; 4];
+    let mut shift = 3 * ROTATION_BITS;
+    let mut length_squared = 0.;
+    for i in (0..4).filter(|&i| i != largest) {
+        shift -= ROTATION_BITS;
+        let unit = ((packed >> shift) & mask) as f32 / mask as f32;
+        components[i] = (unit * 2. - 1.) * SMALLEST_THREE_MAX;
+        length_squared += components[i] * components[i];
+    }
+    components[largest] = (1. - length_squared).max(0.).sqrt();
+
+    Quat::from_array(components).normalize()
+}
+
+fn pack_animation(animation: AnimationState) -> u8 {
+    let ground = match animation.ground {
+        GroundState::Grounded => 0,
+        GroundState::Sliding => 1,
+        GroundState::Airborne => 2,
+    };
+    let jump_count = animation.jump_count.min(MAX_JUMP_COUNT) as u8;
+    ground | ((animation.moving as u8) << 2) | (jump_count << 3)
+}
+
+fn unpack_animation(packed: u8) -> Result<AnimationState, String> {
+    let ground = match packed & 3 {
+        0 => GroundState::Grounded,
+        1 => GroundState::Sliding,
+        2 => GroundState::Airborne,
+        _ => return Err("invalid ground state".into()),
+    };
+    Ok(AnimationState {
+        ground,
+        moving: packed & 4 != 0,
+        jump_count: (packed >> 3) as u32,
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    fn zigzag(&mut self, value: i32) {
+        self.varint(((value << 1) ^ (value >> 31)) as u32 as u64);
+    }
+
+    fn string(&mut self, value: &str) {
+        self.varint(value.len() as u64);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.remaining() < len {
+            return Err("unexpected end of file".into());
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?.try_into().expect("Took 4 bytes.");
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self.take(8)?.try_into().expect("Took 8 bytes.");
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint is too long".into())
+    }
+
+    fn zigzag(&mut self) -> Result<i32, String> {
+        let value = u32::try_from(self.varint()?).map_err(|_| "delta is too large")?;
+        Ok((value >> 1) as i32 ^ -((value & 1) as i32))
+    }
+
+    /// Number of entries that follow, each at least a byte long.
+    fn length(&mut self) -> Result<usize, String> {
+        let len = self.varint()?;
+        if len > self.remaining() as u64 {
+            return Err("unexpected end of file".into());
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.length()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn input(&mut self) -> Result<TickInput, String> {
+        let yaw = f32::from_bits(self.u32()?);
+        let mut actions = vec![];
+        for _ in 0..self.length()? {
+            actions.push(match self.u8()? {
+                0 => {
+                    let x = f32::from_bits(self.u32()?);
+                    let y = f32::from_bits(self.u32()?);
+                    let z = f32::from_bits(self.u32()?);
+                    MovementAction::Move(Vec3::new(x, y, z))
+                }
+                1 => MovementAction::Jump,
+                2 => MovementAction::Reset,
+                tag => return Err(format!("unknown action {tag}")),
+            });
+        }
+        Ok(TickInput { yaw, actions })
+    }
+}
@@ -0,0 +1,348 @@
+//! Player settings, saved to `settings.json` in the data directory and applied live.
+
+#[cfg(not(target_arch = "wasm32"))]
+use bevy::core_pipeline::experimental::taa::TemporalAntiAliasBundle;
+use bevy::{
+    audio::{GlobalVolume, Volume},
+    core_pipeline::bloom::BloomSettings,
+    pbr::DirectionalLightShadowMap,
+    prelude::*,
+    window::PresentMode,
+};
+use bevy_egui::{
+    egui::{self, Color32, Frame, Margin},
+    EguiContexts,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera::{CameraDistance, CameraMode, CameraSettings, LeashedCamera},
+    hud::{HudCorner, HudSettings},
+    storage::{self, StorageErrors},
+    ui::show_storage_errors,
+    State,
+};
+
+const SETTINGS_FILE: &str = "settings.json";
+
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Settings>()
+            .add_systems(
+                Update,
+                (
+                    apply_camera_settings,
+                    apply_graphics_settings.run_if(resource_changed::<Settings>()),
+                ),
+            )
+            .add_systems(Update, ui_settings.run_if(in_state(State::Settings)));
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ShadowQuality {
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+impl ShadowQuality {
+    const ALL: [ShadowQuality; 4] = [
+        ShadowQuality::Off,
+        ShadowQuality::Low,
+        ShadowQuality::Medium,
+        ShadowQuality::High,
+    ];
+
+    /// Size of the [`DirectionalLightShadowMap`], `None` disables shadows.
+    fn map_size(self) -> Option<usize> {
+        match self {
+            ShadowQuality::Off => None,
+            ShadowQuality::Low => Some(1024),
+            ShadowQuality::Medium => Some(2048),
+            ShadowQuality::High => Some(4096),
+        }
+    }
+}
+
+#[derive(Resource, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub mouse_sensitivity: f32,
+    pub invert_y: bool,
+    pub camera: CameraSettings,
+    pub shadow_quality: ShadowQuality,
+    pub bloom: bool,
+    /// Temporal anti-aliasing, not available on the web.
+    pub taa: bool,
+    pub vsync: bool,
+    /// Volume of all audio, 1 is unchanged.
+    pub master_volume: f32,
+    /// Volume of sound effects relative to the master volume.
+    pub effects_volume: f32,
+    pub hud: HudSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            mouse_sensitivity: 0.1,
+            invert_y: false,
+            camera: CameraSettings::default(),
+            shadow_quality: ShadowQuality::Medium,
+            bloom: true,
+            taa: true,
+            vsync: false,
+            master_volume: 1.,
+            effects_volume: 1.,
+            hud: HudSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn present_mode(&self) -> PresentMode {
+        if self.vsync {
+            PresentMode::AutoVsync
+        } else if cfg!(target_os = "macos") {
+            // Mailbox isn't supported on macOS.
+            PresentMode::AutoNoVsync
+        } else {
+            PresentMode::Mailbox
+        }
+    }
+
+    /// Playback settings for a sound effect.
+    pub fn effect(&self) -> PlaybackSettings {
+        PlaybackSettings {
+            volume: Volume::new_relative(self.effects_volume),
+            ..PlaybackSettings::ONCE
+        }
+    }
+
+    /// Loads the settings, falling back to the defaults.
+    pub fn load(errors: &mut StorageErrors) -> Self {
+        #[cfg(target_arch = "wasm32")]
+        return Self::default();
+
+        storage::load_json_or_default(SETTINGS_FILE, errors)
+    }
+
+    pub fn save(&self, errors: &mut StorageErrors) {
+        #[cfg(not(target_arch = "wasm32"))]
+        errors.report(storage::save_json(SETTINGS_FILE, self));
+    }
+}
+
+/// Applies the camera settings to new cameras and to all cameras when the settings change.
+fn apply_camera_settings(
+    mut commands: Commands,
+    settings: Res<Settings>,
+    mut cameras: Query<(Entity, &mut Projection, &mut CameraDistance), With<LeashedCamera>>,
+    added: Query<(), Added<LeashedCamera>>,
+) {
+    if !settings.is_changed() && added.is_empty() {
+        return;
+    }
+
+    for (entity, mut projection, mut distance) in &mut cameras {
+        let mode = settings.camera.current();
+        if let Projection::Perspective(perspective) = &mut *projection {
+            perspective.fov = mode.fov.to_radians();
+        }
+        distance.0 = mode.distance;
+
+        let mut camera = commands.entity(entity);
+        if settings.bloom {
+            camera.insert(BloomSettings::default());
+        } else {
+            camera.remove::<BloomSettings>();
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if settings.taa {
+            camera.insert(TemporalAntiAliasBundle::default());
+        } else {
+            camera.remove::<TemporalAntiAliasBundle>();
+        }
+    }
+}
+
+fn apply_graphics_settings(
+    settings: Res<Settings>,
+    mut shadow_map: ResMut<DirectionalLightShadowMap>,
+    mut lights: Query<&mut DirectionalLight>,
+    mut windows: Query<&mut Window>,
+    mut volume: ResMut<GlobalVolume>,
+) {
+    let map_size = settings.shadow_quality.map_size();
+    if let Some(size) = map_size {
+        shadow_map.size = size;
+    }
+    for mut light in &mut lights {
+        light.shadows_enabled = map_size.is_some();
+    }
+
+    for mut window in &mut windows {
+        window.present_mode = settings.present_mode();
+    }
+
+    *volume = GlobalVolume::new(settings.master_volume);
+}
+
+/// Controls for every setting. Changes apply right away, saving is up to the caller.
+pub fn show_settings(ui: &mut egui::Ui, settings: &mut Settings) {
+    egui::Grid::new("settings").show(ui, |ui| {
+        ui.label("Mouse sensitivity");
+        ui.add(
+            egui::DragValue::new(&mut settings.mouse_sensitivity)
+                .clamp_range(0.01..=1.)
+                .speed(0.005),
+        );
+        ui.end_row();
+
+        ui.label("Invert Y");
+        ui.checkbox(&mut settings.invert_y, "");
+        ui.end_row();
+
+        let camera = &mut settings.camera;
+        ui.label("Camera");
+        egui::ComboBox::from_id_source("camera mode")
+            .selected_text(camera.mode.label())
+            .show_ui(ui, |ui| {
+                for mode in CameraMode::ALL {
+                    ui.selectable_value(&mut camera.mode, mode, mode.label());
+                }
+            });
+        ui.end_row();
+
+        // The sliders below change the selected mode.
+        let mode = camera.mode;
+        let mode_settings = camera.get_mut(mode);
+        ui.label("Field of view");
+        ui.add(egui::Slider::new(&mut mode_settings.fov, 30.0..=120.0).suffix("°"));
+        ui.end_row();
+
+        if mode != CameraMode::FirstPerson {
+            ui.label("Camera distance");
+            ui.add(egui::Slider::new(&mut mode_settings.distance, 5.0..=60.0));
+            ui.end_row();
+        }
+
+        ui.label("Camera smoothing");
+        ui.add(egui::Slider::new(&mut mode_settings.smoothing, 0.0..=1.0).suffix(" s"));
+        ui.end_row();
+
+        ui.label("Shadows");
+        egui::ComboBox::from_id_source("shadow quality")
+            .selected_text(format!("{:?}", settings.shadow_quality))
+            .show_ui(ui, |ui| {
+                for quality in ShadowQuality::ALL {
+                    ui.selectable_value(
+                        &mut settings.shadow_quality,
+                        quality,
+                        format!("{quality:?}"),
+                    );
+                }
+            });
+        ui.end_row();
+
+        ui.label("Bloom");
+        ui.checkbox(&mut settings.bloom, "");
+        ui.end_row();
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            ui.label("Anti-aliasing (TAA)");
+            ui.checkbox(&mut settings.taa, "");
+            ui.end_row();
+        }
+
+        ui.label("Vsync");
+        ui.checkbox(&mut settings.vsync, "");
+        ui.end_row();
+
+        ui.label("Master volume");
+        ui.add(egui::Slider::new(&mut settings.master_volume, 0.0..=1.0));
+        ui.end_row();
+
+        ui.label("Effects volume");
+        ui.add(egui::Slider::new(&mut settings.effects_volume, 0.0..=1.0));
+        ui.end_row();
+
+        let hud = &mut settings.hud;
+        ui.label("HUD");
+        ui.checkbox(&mut hud.visible, "");
+        ui.end_row();
+
+        ui.label("HUD position");
+        egui::ComboBox::from_id_source("hud corner")
+            .selected_text(format!("{:?}", hud.corner))
+            .show_ui(ui, |ui| {
+                for corner in HudCorner::ALL {
+                    ui.selectable_value(&mut hud.corner, corner, format!("{corner:?}"));
+                }
+            });
+        ui.end_row();
+
+        ui.label("HUD size");
+        ui.add(egui::Slider::new(&mut hud.size, 10.0..=40.0));
+        ui.end_row();
+
+        ui.label("HUD shows");
+        ui.vertical(|ui| {
+            ui.checkbox(&mut hud.timer, "Time");
+            ui.checkbox(&mut hud.speed, "Speed");
+            ui.checkbox(&mut hud.multiplier, "Multiplier");
+            ui.checkbox(&mut hud.air_jumps, "Air jumps");
+            ui.checkbox(&mut hud.ground_state, "Grounded / sliding");
+            ui.checkbox(&mut hud.checkpoints, "Checkpoints");
+            ui.checkbox(&mut hud.trails, "Path trails");
+        });
+        ui.end_row();
+    });
+}
+
+fn ui_settings(
+    mut contexts: EguiContexts,
+    mut state: ResMut<NextState<State>>,
+    mut settings: ResMut<Settings>,
+    mut errors: ResMut<StorageErrors>,
+) {
+    let ctx = contexts.ctx_mut();
+    egui::Area::new("settings").show(ctx, |ui| {
+        Frame {
+            outer_margin: Margin::symmetric(300., 0.),
+            inner_margin: Margin::same(20.),
+            fill: Color32::from_rgba_unmultiplied(255, 255, 255, 150),
+            ..Default::default()
+        }
+        .show(ui, |ui| {
+            ui.vertical_centered(|ui| {
+                ui.heading("Settings");
+
+                // Only touch the resource on edits, so the change detection above stays quiet.
+                let mut edited = settings.clone();
+                show_settings(ui, &mut edited);
+                if edited != *settings {
+                    *settings = edited;
+                }
+
+                show_storage_errors(ui, &mut errors);
+
+                ui.horizontal(|ui| {
+                    if ui.button("Reset to defaults").clicked() {
+                        *settings = Settings::default();
+                    }
+                    if ui.button("Back").clicked() {
+                        settings.save(&mut errors);
+                        state.set(State::Mainscreen);
+                    }
+                });
+            })
+        })
+    });
+}
@@ -0,0 +1,378 @@
+use std::{collections::HashMap, path::Path};
+
+use bevy::prelude::*;
+use bevy_egui::{
+    egui::{self, Align2, Color32, RichText},
+    EguiContexts,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    map::Map,
+    replay::ReplayPlayback,
+    storage::{self, StorageErrors},
+    timing::{format_seconds, MapDuration},
+    tuning::Tuned,
+    Player, State,
+};
+
+const SPLITS_FILE: &str = "splits.json";
+
+/// How long a split stays on screen after crossing a checkpoint, in seconds.
+const SPLIT_DISPLAY_TIME: f32 = 3.;
+
+pub struct SplitsPlugin;
+
+impl Plugin for SplitsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, load_split_records)
+            .add_systems(Update, display_split.run_if(in_state(State::Playing)))
+            .add_systems(OnEnter(State::Finished), save_splits);
+    }
+}
+
+/// Time since the start when a checkpoint was crossed.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Split {
+    /// Laps completed before this split.
+    #[serde(default)]
+    pub lap: usize,
+    /// Index of the checkpoint in [`Map::checkpoints`].
+    /// The start/finish line of a circuit comes after the last checkpoint.
+    pub checkpoint: usize,
+    /// In seconds.
+    pub time: f32,
+}
+
+/// The splits of one run, in the order the checkpoints were crossed.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct RunSplits {
+    pub splits: Vec<Split>,
+    /// Time at the goal in seconds, if the run finished.
+    pub finish: Option<f32>,
+}
+
+impl RunSplits {
+    pub fn split_for(&self, lap: usize, checkpoint: usize) -> Option<f32> {
+        self.splits
+            .iter()
+            .find(|split| split.lap == lap && split.checkpoint == checkpoint)
+            .map(|split| split.time)
+    }
+
+    /// Time between consecutive checkpoints. The first sector starts at the start,
+    /// the last one ends at the goal once the run is finished.
+    /// Only comparable between runs on maps with a [`fixed_route`].
+    pub fn sectors(&self) -> Vec<f32> {
+        let mut previous = 0.;
+        self.splits
+            .iter()
+            .map(|split| split.time)
+            .chain(self.finish)
+            .map(|time| {
+                let sector = time - previous;
+                previous = time;
+                sector
+            })
+            .collect()
+    }
+}
+
+/// The splits of the run in progress, on the [`Player`].
+#[derive(Component, Default)]
+pub struct Splits(pub RunSplits);
+
+impl Splits {
+    /// Records crossing a checkpoint `fraction` of the way through the latest tick.
+    pub fn record(&mut self, checkpoint: usize, duration: &MapDuration, fraction: f64) {
+        self.0.splits.push(Split {
+            lap: duration.laps_completed(),
+            checkpoint,
+            time: duration.during_tick(fraction).as_secs_f32(),
+        });
+    }
+
+    pub fn finish(&mut self, duration: &MapDuration) {
+        self.0.finish = Some(duration.elapsed().as_secs_f32());
+    }
+}
+
+/// Whether every run crosses the checkpoints of the map in the same order,
+/// otherwise the sectors of two runs can be between different checkpoints.
+pub fn fixed_route(map: &Map) -> bool {
+    map.ordered_checkpoints || map.checkpoints.len() <= 1
+}
+
+/// The personal best and the best time of every sector on one map.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct MapSplits {
+    pub personal_best: Option<RunSplits>,
+    /// Empty on maps without a [`fixed_route`].
+    pub best_sectors: Vec<f32>,
+}
+
+impl MapSplits {
+    fn add_run(&mut self, run: &RunSplits, fixed_route: bool) {
+        let Some(finish) = run.finish else {
+            return;
+        };
+
+        if self
+            .personal_best
+            .as_ref()
+            .and_then(|pb| pb.finish)
+            .map_or(true, |pb| finish < pb)
+        {
+            self.personal_best = Some(run.clone());
+        }
+
+        let sectors = run.sectors();
+        if !fixed_route {
+            self.best_sectors.clear();
+        } else if self.best_sectors.len() != sectors.len() {
+            // The map's checkpoints changed, older sectors can't be compared anymore.
+            self.best_sectors = sectors;
+        } else {
+            for (best, sector) in self.best_sectors.iter_mut().zip(sectors) {
+                *best = best.min(sector);
+            }
+        }
+    }
+
+    /// The theoretical time when every sector is driven as fast as it ever was.
+    pub fn sum_of_best(&self) -> Option<f32> {
+        if self.best_sectors.is_empty() {
+            None
+        } else {
+            Some(self.best_sectors.iter().sum())
+        }
+    }
+}
+
+/// The records of every map, see [`SplitRecords::key`].
+#[derive(Default, Resource, Serialize, Deserialize)]
+pub struct SplitRecords {
+    maps: HashMap<String, MapSplits>,
+}
+
+impl SplitRecords {
+    /// Records are kept per map and version of its profile, like the leaderboard ranks them.
+    /// Ones keyed by the map name alone predate that and are no longer used.
+    fn key(map: &Map) -> String {
+        format!("{}/{}/{:016x}", map.name, map.profile, map.movement.hash())
+    }
+
+    pub fn personal_best(&self, map: &Map) -> Option<&RunSplits> {
+        self.maps.get(&Self::key(map))?.personal_best.as_ref()
+    }
+}
+
+/// The run shown on the finish screen, compared to the records from before it.
+#[derive(Resource)]
+pub struct FinishedRun {
+    pub run: RunSplits,
+    pub previous: MapSplits,
+    pub sum_of_best: Option<f32>,
+    /// See [`fixed_route`], without one the run is compared checkpoint by checkpoint.
+    pub fixed_route: bool,
+}
+
+fn load_split_records(mut commands: Commands, mut errors: ResMut<StorageErrors>) {
+    #[cfg(target_arch = "wasm32")]
+    {
+        commands.init_resource::<SplitRecords>();
+        return;
+    }
+
+    errors.report(storage::migrate_legacy(
+        &Path::new("maps").join(SPLITS_FILE),
+        SPLITS_FILE,
+    ));
+    let records: SplitRecords = storage::load_json_or_default(SPLITS_FILE, &mut errors);
+    commands.insert_resource(records);
+}
+
+fn save_splits(
+    mut commands: Commands,
+    map: Res<Map>,
+    // Watched replays and tuned runs don't count.
+    player: Query<&Splits, (With<Player>, Without<ReplayPlayback>, Without<Tuned>)>,
+    mut records: ResMut<SplitRecords>,
+    mut errors: ResMut<StorageErrors>,
+) {
+    let Ok(splits) = player.get_single() else {
+        return;
+    };
+
+    let record = records.maps.entry(SplitRecords::key(&map)).or_default();
+    let previous = record.clone();
+    record.add_run(&splits.0, fixed_route(&map));
+
+    commands.insert_resource(FinishedRun {
+        run: splits.0.clone(),
+        previous,
+        sum_of_best: record.sum_of_best(),
+        fixed_route: fixed_route(&map),
+    });
+
+    #[cfg(not(target_arch = "wasm32"))]
+    errors.report(storage::save_json(SPLITS_FILE, &*records));
+}
+
+pub fn format_delta(delta: f32) -> (String, Color32) {
+    if delta <= 0. {
+        (format!("-{:.3}", -delta), Color32::DARK_GREEN)
+    } else {
+        (format!("+{delta:.3}"), Color32::DARK_RED)
+    }
+}
+
+/// Shows the latest split and its delta to the personal best for a few seconds.
+fn display_split(
+    mut contexts: EguiContexts,
+    map: Res<Map>,
+    records: Res<SplitRecords>,
+    player: Query<(&Splits, &MapDuration), With<Player>>,
+) {
+    let Ok((splits, duration)) = player.get_single() else {
+        return;
+    };
+    let Some(split) = splits.0.splits.last() else {
+        return;
+    };
+    if duration.elapsed().as_secs_f32() - split.time > SPLIT_DISPLAY_TIME {
+        return;
+    }
+
+    let delta = records
+        .personal_best(&map)
+        .and_then(|pb| pb.split_for(split.lap, split.checkpoint))
+        .map(|pb| format_delta(split.time - pb));
+
+    egui::Area::new("split")
+        .anchor(Align2::CENTER_TOP, [0., 80.])
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(format_seconds(split.time)).size(30.));
+                if let Some((delta, color)) = delta {
+                    ui.label(RichText::new(delta).size(30.).color(color));
+                }
+            });
+        });
+}
+
+/// Per-sector breakdown of a finished run for the finish screen.
+pub fn show_breakdown(ui: &mut egui::Ui, finished: &FinishedRun) {
+    if !finished.fixed_route {
+        show_checkpoint_breakdown(ui, finished);
+        return;
+    }
+
+    let pb_sectors = finished
+        .previous
+        .personal_best
+        .as_ref()
+        .map(RunSplits::sectors)
+        .unwrap_or_default();
+
+    egui::Grid::new("sectors").striped(true).show(ui, |ui| {
+        ui.label("Sector");
+        ui.label("Time");
+        ui.label("PB");
+        ui.label("Best");
+        ui.end_row();
+
+        for (i, sector) in finished.run.sectors().into_iter().enumerate() {
+            ui.label(format!("{}", i + 1));
+            ui.label(format_seconds(sector));
+            match pb_sectors.get(i) {
+                Some(pb) => {
+                    let (delta, color) = format_delta(sector - pb);
+                    ui.colored_label(color, delta);
+                }
+                None => {
+                    ui.label("-");
+                }
+            }
+            match finished.previous.best_sectors.get(i) {
+                Some(best) => ui.label(format_seconds(*best)),
+                None => ui.label("-"),
+            };
+            ui.end_row();
+        }
+    });
+
+    if let Some(sum_of_best) = finished.sum_of_best {
+        ui.label(format!("Sum of best: {}", format_seconds(sum_of_best)));
+    }
+}
+
+/// Per-checkpoint breakdown of a finished run, for maps where the sectors can't be compared.
+fn show_checkpoint_breakdown(ui: &mut egui::Ui, finished: &FinishedRun) {
+    let pb = finished.previous.personal_best.as_ref();
+
+    egui::Grid::new("checkpoints").striped(true).show(ui, |ui| {
+        ui.label("Checkpoint");
+        ui.label("Time");
+        ui.label("PB");
+        ui.end_row();
+
+        for split in &finished.run.splits {
+            ui.label(format!("{}", split.checkpoint + 1));
+            ui.label(format_seconds(split.time));
+            match pb.and_then(|pb| pb.split_for(split.lap, split.checkpoint)) {
+                Some(pb) => {
+                    let (delta, color) = format_delta(split.time - pb);
+                    ui.colored_label(color, delta);
+                }
+                None => {
+                    ui.label("-");
+                }
+            }
+            ui.end_row();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(checkpoints: &[(usize, f32)], finish: f32) -> RunSplits {
+        RunSplits {
+            splits: checkpoints
+                .iter()
+                .map(|&(checkpoint, time)| Split {
+                    lap: 0,
+                    checkpoint,
+                    time,
+                })
+                .collect(),
+            finish: Some(finish),
+        }
+    }
+
+    #[test]
+    fn best_sectors_are_kept_on_a_fixed_route() {
+        let mut record = MapSplits::default();
+        record.add_run(&run(&[(0, 10.), (1, 30.)], 40.), true);
+        record.add_run(&run(&[(0, 12.), (1, 25.)], 41.), true);
+
+        assert_eq!(record.best_sectors, [10., 13., 10.]);
+        assert_eq!(record.sum_of_best(), Some(33.));
+        assert_eq!(record.personal_best.unwrap().finish, Some(40.));
+    }
+
+    #[test]
+    fn runs_in_another_order_have_no_best_sectors() {
+        let mut record = MapSplits::default();
+        record.add_run(&run(&[(0, 10.), (1, 30.)], 40.), false);
+        record.add_run(&run(&[(1, 5.), (0, 25.)], 45.), false);
+
+        assert!(record.best_sectors.is_empty());
+        assert_eq!(record.sum_of_best(), None);
+        // Checkpoints are still compared by their index.
+        let pb = record.personal_best.unwrap();
+        assert_eq!(pb.split_for(0, 1), Some(30.));
+    }
+}
@@ -0,0 +1,395 @@
+//! Persistent player data: highscores, splits, replays and settings.
+//!
+//! Everything is stored in a per-user data directory instead of the `maps/` content directory.
+//! Files are written to a temporary file first and renamed over the old one,
+//! so a crash never leaves a half written file behind. The previous version is kept as a backup.
+
+use std::{
+    env, fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+const APP_DIR: &str = "gottagofaster";
+
+#[cfg(test)]
+thread_local! {
+    /// Overrides [`data_dir`] for the test running on this thread.
+    static TEST_DATA_DIR: std::cell::RefCell<Option<PathBuf>> = Default::default();
+}
+
+/// Reasons reading or writing player data can fail.
+#[derive(Debug)]
+pub enum StorageError {
+    /// There is no per-user data directory, e.g. because `HOME` isn't set.
+    NoDataDir,
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The file couldn't be parsed. It was moved to `quarantined`, so it can be inspected or fixed.
+    Corrupt {
+        path: PathBuf,
+        quarantined: PathBuf,
+        message: String,
+        /// Whether the backup of the previous version took its place.
+        restored_backup: bool,
+    },
+    Serialize {
+        path: PathBuf,
+        message: String,
+    },
+    /// A file couldn't be used, it is left untouched.
+    /// Either one from outside the data directory or one this build can't read, like a newer version.
+    Invalid {
+        path: PathBuf,
+        message: String,
+    },
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NoDataDir => write!(f, "no user data directory found"),
+            StorageError::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            StorageError::Corrupt {
+                path,
+                quarantined,
+                message,
+                restored_backup,
+            } => {
+                write!(
+                    f,
+                    "{} is corrupt ({message}), moved it to {}",
+                    path.display(),
+                    quarantined.display()
+                )?;
+                if *restored_backup {
+                    write!(f, " and restored the backup")?;
+                }
+                Ok(())
+            }
+            StorageError::Serialize { path, message } => {
+                write!(f, "could not serialize {}: {message}", path.display())
+            }
+            StorageError::Invalid { path, message } => {
+                write!(f, "could not use {}: {message}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Storage errors that haven't been shown to the player yet.
+#[derive(Resource, Default)]
+pub struct StorageErrors(pub Vec<StorageError>);
+
+impl StorageErrors {
+    /// Keeps the error of a failed operation so the UI can show it.
+    pub fn report<T>(&mut self, result: Result<T, StorageError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                println!("Storage error: {e}");
+                // Without a data directory every operation fails the same way.
+                let repeated = matches!(e, StorageError::NoDataDir)
+                    && self.0.iter().any(|e| matches!(e, StorageError::NoDataDir));
+                if !repeated {
+                    self.0.push(e);
+                }
+                None
+            }
+        }
+    }
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> StorageError + '_ {
+    move |source| StorageError::Io {
+        path: path.to_owned(),
+        source,
+    }
+}
+
+/// Appends a suffix to the file name, `highscores.json` becomes `highscores.json.{suffix}`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+pub fn unix_time() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    return 0;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The per-user directory all player data is stored in.
+pub fn data_dir() -> Result<PathBuf, StorageError> {
+    #[cfg(test)]
+    if let Some(dir) = TEST_DATA_DIR.with(|dir| dir.borrow().clone()) {
+        return Ok(dir);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    return Err(StorageError::NoDataDir);
+
+    #[cfg(target_os = "windows")]
+    let base = env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(target_os = "macos")]
+    let base = env::var_os("HOME").map(|home| Path::new(&home).join("Library/Application Support"));
+    #[cfg(not(any(target_os = "windows", target_os = "macos", target_arch = "wasm32")))]
+    let base = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")));
+
+    #[cfg(not(target_arch = "wasm32"))]
+    base.map(|base| base.join(APP_DIR))
+        .ok_or(StorageError::NoDataDir)
+}
+
+/// Absolute path of a file in the data directory.
+pub fn path(relative: impl AsRef<Path>) -> Result<PathBuf, StorageError> {
+    Ok(data_dir()?.join(relative))
+}
+
+/// Reads a file from the data directory. Returns `None` if it doesn't exist.
+pub fn read(relative: impl AsRef<Path>) -> Result<Option<Vec<u8>>, StorageError> {
+    let path = path(relative)?;
+    let mut contents = vec![];
+    match File::open(&path) {
+        Ok(mut file) => {
+            file.read_to_end(&mut contents).map_err(io_error(&path))?;
+            Ok(Some(contents))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(io_error(&path)(e)),
+    }
+}
+
+/// Atomically replaces a file in the data directory, keeping the previous version as a backup.
+pub fn write(relative: impl AsRef<Path>, contents: &[u8]) -> Result<(), StorageError> {
+    let path = path(relative)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(io_error(dir))?;
+    }
+
+    let tmp = with_suffix(&path, "tmp");
+    let mut file = File::create(&tmp).map_err(io_error(&tmp))?;
+    file.write_all(contents).map_err(io_error(&tmp))?;
+    file.sync_all().map_err(io_error(&tmp))?;
+
+    if path.exists() {
+        // Copied instead of renamed, so there is never a moment without the file.
+        let backup = with_suffix(&path, "bak");
+        fs::copy(&path, &backup).map_err(io_error(&backup))?;
+    }
+
+    fs::rename(&tmp, &path).map_err(io_error(&path))
+}
+
+pub fn remove(relative: impl AsRef<Path>) -> Result<(), StorageError> {
+    let path = path(relative)?;
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_error(&path)(e)),
+        _ => Ok(()),
+    }
+}
+
+/// Moves a corrupt file aside and puts its backup in its place, if there is one.
+fn quarantine(path: &Path, message: String) -> StorageError {
+    let quarantined = with_suffix(path, &format!("corrupt-{}", unix_time()));
+    if let Err(e) = fs::rename(path, &quarantined) {
+        return io_error(path)(e);
+    }
+
+    let backup = with_suffix(path, "bak");
+    let restored_backup = backup.exists() && fs::copy(&backup, path).is_ok();
+
+    StorageError::Corrupt {
+        path: path.to_owned(),
+        quarantined,
+        message,
+        restored_backup,
+    }
+}
+
+/// Reads a file and parses it with `parse`. Returns `None` if it doesn't exist.
+///
+/// A file that can't be parsed is quarantined and its backup restored,
+/// so loading again after the error returns the previous version.
+pub fn load<T>(
+    relative: impl AsRef<Path>,
+    parse: impl FnOnce(&[u8]) -> Result<T, String>,
+) -> Result<Option<T>, StorageError> {
+    let Some(contents) = read(&relative)? else {
+        return Ok(None);
+    };
+    match parse(&contents) {
+        Ok(value) => Ok(Some(value)),
+        Err(message) => Err(quarantine(&path(relative)?, message)),
+    }
+}
+
+/// Reads and parses a json file, see [`load`].
+pub fn load_json<T: DeserializeOwned>(
+    relative: impl AsRef<Path>,
+) -> Result<Option<T>, StorageError> {
+    load(relative, |contents| {
+        serde_json::from_slice(contents).map_err(|e| e.to_string())
+    })
+}
+
+/// Like [`load_json`], but reports errors and falls back to the restored backup or the default.
+pub fn load_json_or_default<T: DeserializeOwned + Default>(
+    relative: impl AsRef<Path>,
+    errors: &mut StorageErrors,
+) -> T {
+    match load_json(&relative) {
+        Ok(value) => value.unwrap_or_default(),
+        Err(e) => {
+            let restored = matches!(
+                e,
+                StorageError::Corrupt {
+                    restored_backup: true,
+                    ..
+                }
+            );
+            errors.report::<()>(Err(e));
+            if restored {
+                errors
+                    .report(load_json(&relative))
+                    .flatten()
+                    .unwrap_or_default()
+            } else {
+                T::default()
+            }
+        }
+    }
+}
+
+pub fn save_json<T: Serialize>(relative: impl AsRef<Path>, value: &T) -> Result<(), StorageError> {
+    let contents = serde_json::to_vec(value).map_err(|e| StorageError::Serialize {
+        path: relative.as_ref().to_owned(),
+        message: e.to_string(),
+    })?;
+    write(relative, &contents)
+}
+
+/// Reads and parses a file from anywhere on disk, e.g. one the player wants to import.
+/// Unlike [`load`], the file is never moved or modified.
+pub fn import<T>(
+    path: &Path,
+    parse: impl FnOnce(&[u8]) -> Result<T, String>,
+) -> Result<T, StorageError> {
+    let contents = fs::read(path).map_err(io_error(path))?;
+    parse(&contents).map_err(|message| StorageError::Invalid {
+        path: path.to_owned(),
+        message,
+    })
+}
+
+/// Copies a file from its old location in the content directory into the data directory,
+/// unless the data directory already has it.
+pub fn migrate_legacy(legacy: &Path, relative: impl AsRef<Path>) -> Result<(), StorageError> {
+    if !legacy.exists() || path(&relative)?.exists() {
+        return Ok(());
+    }
+    let contents = fs::read(legacy).map_err(io_error(legacy))?;
+    // Older versions created empty files before ever writing to them.
+    if contents.is_empty() {
+        return Ok(());
+    }
+    println!("Moving {} into the user data directory", legacy.display());
+    write(relative, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Points the data directory of this thread to an empty temporary one.
+    fn temp_data_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("{APP_DIR}-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TEST_DATA_DIR.with(|test_dir| *test_dir.borrow_mut() = Some(dir.clone()));
+        dir
+    }
+
+    #[test]
+    fn corrupt_file_is_quarantined() {
+        let dir = temp_data_dir("corrupt");
+        fs::write(dir.join("scores.json"), "[1, 2").unwrap();
+        let mut errors = StorageErrors::default();
+
+        let scores: Vec<u32> = load_json_or_default("scores.json", &mut errors);
+
+        assert!(scores.is_empty());
+        let [StorageError::Corrupt {
+            quarantined,
+            restored_backup: false,
+            ..
+        }] = &errors.0[..]
+        else {
+            panic!("Expected a corrupt file, got {:?}", errors.0);
+        };
+        assert_eq!(fs::read_to_string(quarantined).unwrap(), "[1, 2");
+        assert!(!dir.join("scores.json").exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn backup_replaces_corrupt_file() {
+        let dir = temp_data_dir("backup");
+        save_json("scores.json", &[1]).unwrap();
+        save_json("scores.json", &[1, 2]).unwrap();
+        fs::write(dir.join("scores.json"), "[1, 2").unwrap();
+        let mut errors = StorageErrors::default();
+
+        let scores: Vec<u32> = load_json_or_default("scores.json", &mut errors);
+
+        assert_eq!(scores, [1]);
+        assert!(matches!(
+            &errors.0[..],
+            [StorageError::Corrupt {
+                restored_backup: true,
+                ..
+            }]
+        ));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn legacy_file_is_migrated_once() {
+        let dir = temp_data_dir("legacy");
+        let legacy = dir.join("winter.replay");
+        fs::write(&legacy, "old").unwrap();
+
+        migrate_legacy(&legacy, "ghosts/winter.replay").unwrap();
+        assert_eq!(read("ghosts/winter.replay").unwrap().unwrap(), b"old");
+
+        // The copy in the data directory is newer from now on.
+        fs::write(&legacy, "older").unwrap();
+        migrate_legacy(&legacy, "ghosts/winter.replay").unwrap();
+        assert_eq!(read("ghosts/winter.replay").unwrap().unwrap(), b"old");
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,124 @@
+//! Surfaces of the map geometry that change how the character controller moves on them.
+//!
+//! A mesh opts in through its glTF node or mesh name, like `ice_ramp` or `Boost.001`,
+//! or through its extras, like `{"surface": "ice"}`.
+
+use bevy::{gltf::GltfExtras, prelude::*};
+use bevy_xpbd_3d::math::Scalar;
+use serde::Deserialize;
+
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Surface {
+    /// Barely any friction, speed carries over and slopes can't be stood on.
+    Ice,
+    /// No friction and pushes the character along its horizontal velocity.
+    Boost,
+}
+
+impl Surface {
+    const ALL: [Surface; 2] = [Surface::Ice, Surface::Boost];
+
+    fn tag(self) -> &'static str {
+        match self {
+            Surface::Ice => "ice",
+            Surface::Boost => "boost",
+        }
+    }
+
+    /// Part of the ground damping applied on this surface.
+    pub fn friction(self) -> Scalar {
+        match self {
+            Surface::Ice => 0.1,
+            Surface::Boost => 0.,
+        }
+    }
+
+    /// Acceleration along the horizontal velocity while standing on this surface.
+    pub fn boost(self) -> Scalar {
+        match self {
+            Surface::Ice => 0.,
+            Surface::Boost => 40.,
+        }
+    }
+
+    /// Whether standing still on a walkable slope of this surface still slides down it.
+    pub fn is_slippery(self) -> bool {
+        matches!(self, Surface::Ice)
+    }
+
+    /// The surface a name is tagged with, by its first word.
+    fn from_name(name: &str) -> Option<Self> {
+        let word = name
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .next()?
+            .to_ascii_lowercase();
+        Self::ALL.into_iter().find(|surface| surface.tag() == word)
+    }
+
+    /// The surface of a `surface` key in the extras. Extras are shared with other tools,
+    /// anything else in them is none of our business.
+    fn from_extras(extras: &GltfExtras) -> Option<Self> {
+        let extras: serde_json::Value = serde_json::from_str(&extras.value).ok()?;
+        let surface = extras.get("surface")?;
+
+        match Surface::deserialize(surface) {
+            Ok(surface) => Some(surface),
+            Err(e) => {
+                println!("Ignoring glTF surface {surface}: {e}");
+                None
+            }
+        }
+    }
+
+    /// The surface a glTF entity opts into, extras take precedence over the name.
+    pub fn of(name: Option<&Name>, extras: Option<&GltfExtras>) -> Option<Self> {
+        extras
+            .and_then(Self::from_extras)
+            .or_else(|| name.and_then(|name| Self::from_name(name.as_str())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(name: &str) -> Name {
+        Name::new(name.to_string())
+    }
+
+    fn extras(value: &str) -> GltfExtras {
+        GltfExtras {
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn surfaces_are_tagged_by_name() {
+        assert_eq!(
+            Surface::of(Some(&name("ice_ramp")), None),
+            Some(Surface::Ice)
+        );
+        assert_eq!(
+            Surface::of(Some(&name("Boost.001")), None),
+            Some(Surface::Boost)
+        );
+        assert_eq!(Surface::of(Some(&name("Iceberg")), None), None);
+    }
+
+    #[test]
+    fn surfaces_are_tagged_by_extras() {
+        assert_eq!(
+            Surface::of(Some(&name("Cube")), Some(&extras(r#"{"surface": "ice"}"#))),
+            Some(Surface::Ice)
+        );
+        assert_eq!(
+            Surface::of(Some(&name("Cube")), Some(&extras(r#"{"color": "red"}"#))),
+            None
+        );
+        assert_eq!(
+            Surface::of(Some(&name("Cube")), Some(&extras(r#"{"surface": "lava"}"#))),
+            None
+        );
+    }
+}
@@ -1,35 +1,94 @@
 use bevy::prelude::*;
-use instant::{Duration, Instant};
+use instant::Duration;
 
-use crate::Player;
+use crate::{physics::TICK_RATE, splits::Splits, Player};
 
-#[derive(Component)]
+/// The clock of a run. It counts simulation ticks, so frame rate, hitches and pauses
+/// don't change the time and the same input always gives the same time.
+#[derive(Component, Default)]
 pub struct MapDuration {
-    start: Instant,
-    end: Option<Instant>,
+    /// Ticks simulated since the start.
+    ticks: u32,
+    /// Time since the start when the goal was crossed.
+    end: Option<Duration>,
+    /// Time since the start at the end of every completed lap.
+    laps: Vec<Duration>,
 }
 
 impl MapDuration {
     pub fn new() -> Self {
-        Self {
-            start: Instant::now(),
-            end: None,
-        }
+        Self::default()
+    }
+
+    /// Time since the start at a point during the latest tick, `fraction` 0 is its start and 1 its end.
+    /// Used for crossings that happened in between two ticks.
+    pub fn during_tick(&self, fraction: f64) -> Duration {
+        let ticks = self.ticks as f64 - 1. + fraction.clamp(0., 1.);
+        Duration::from_secs_f64(ticks.max(0.) / TICK_RATE)
+    }
+
+    pub fn complete_lap(&mut self, fraction: f64) {
+        let time = self.during_tick(fraction);
+        self.laps.push(time);
     }
 
-    pub fn stop(&mut self) {
-        self.end = Some(Instant::now());
+    pub fn laps_completed(&self) -> usize {
+        self.laps.len()
+    }
+
+    /// Duration of every completed lap.
+    pub fn lap_times(&self) -> Vec<Duration> {
+        let mut previous = Duration::ZERO;
+        self.laps
+            .iter()
+            .map(|&end| {
+                let lap = end - previous;
+                previous = end;
+                lap
+            })
+            .collect()
+    }
+
+    pub fn best_lap(&self) -> Duration {
+        self.lap_times().into_iter().min().unwrap_or_default()
+    }
+
+    /// Ends the run at a point during the latest tick, see [`MapDuration::during_tick`].
+    pub fn stop(&mut self, fraction: f64) {
+        self.end = Some(self.during_tick(fraction));
     }
 
     pub fn elapsed(&self) -> Duration {
-        if let Some(end) = self.end {
-            end - self.start
-        } else {
-            Instant::now() - self.start
+        self.end
+            .unwrap_or_else(|| Duration::from_secs_f64(self.ticks as f64 / TICK_RATE))
+    }
+}
+
+/// Advances the clock of every running run by one tick, in the tick its input is simulated.
+pub fn advance_clock(mut durations: Query<&mut MapDuration>) {
+    for mut duration in &mut durations {
+        if duration.end.is_none() {
+            duration.ticks += 1;
         }
     }
 }
 
+/// A time as `m:ss.mmm`, or `s.mmm` below a minute, rounded to the millisecond.
+pub fn format_time(time: Duration) -> String {
+    let millis = (time.as_secs_f64() * 1000.).round() as u64;
+    let (minutes, seconds, millis) = (millis / 60_000, millis / 1000 % 60, millis % 1000);
+    if minutes > 0 {
+        format!("{minutes}:{seconds:02}.{millis:03}")
+    } else {
+        format!("{seconds}.{millis:03}")
+    }
+}
+
+/// Like [`format_time`], for times stored as seconds.
+pub fn format_seconds(seconds: f32) -> String {
+    format_time(Duration::from_secs_f32(seconds.max(0.)))
+}
+
 #[derive(Component)]
 pub struct Countdown(pub Timer);
 
@@ -61,7 +120,7 @@ pub fn countdown_timer(
                 commands
                     .get_entity(player)
                     .unwrap()
-                    .insert(MapDuration::new());
+                    .insert((MapDuration::new(), Splits::default()));
             }
         }
     }
@@ -0,0 +1,100 @@
+//! Paths of the player's run and of the ghosts, drawn on the map as lines coloured by speed.
+//! Next to each other they show where another run took a different line or used a pad.
+
+use bevy::prelude::*;
+
+use crate::{
+    bindings::{Actions, InputAction},
+    ghost::Ghost,
+    map::Map,
+    replay::{Replay, ReplayRecorder, TrackPlayback},
+    settings::Settings,
+    storage::StorageErrors,
+    Player, State,
+};
+
+/// Trails are drawn this far above the track, so they aren't hidden in the ground.
+const TRAIL_OFFSET: Vec3 = Vec3::new(0., 0.2, 0.);
+
+/// Trails start fading out this far from the camera and are gone at [`FADE_END`].
+const FADE_START: f32 = 30.;
+const FADE_END: f32 = 150.;
+
+/// Moving further than this in one tick is a teleport, like a reset to a checkpoint.
+/// The trail is broken there instead of crossing the map.
+const MAX_STEP: f32 = 5.;
+
+pub struct TrailPlugin;
+
+impl Plugin for TrailPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (toggle_trails, draw_trails)
+                .chain()
+                .run_if(in_state(State::Playing)),
+        );
+    }
+}
+
+fn toggle_trails(
+    actions: Actions,
+    mut settings: ResMut<Settings>,
+    mut errors: ResMut<StorageErrors>,
+) {
+    if actions.just_pressed(InputAction::ToggleTrails) {
+        settings.hud.trails = !settings.hud.trails;
+        settings.save(&mut errors);
+    }
+}
+
+/// Blue when standing still, through green and yellow to red at `fast` and above.
+pub fn speed_color(speed: f32, fast: f32) -> Color {
+    let fraction = (speed / fast).clamp(0., 1.);
+    Color::hsl(240. * (1. - fraction), 1., 0.5)
+}
+
+fn draw_trails(
+    mut gizmos: Gizmos,
+    settings: Res<Settings>,
+    map: Res<Map>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    player: Query<&ReplayRecorder, With<Player>>,
+    ghosts: Query<&TrackPlayback, With<Ghost>>,
+) {
+    if !settings.hud.trails {
+        return;
+    }
+    let Some((_, camera)) = cameras.iter().find(|(camera, _)| camera.is_active) else {
+        return;
+    };
+    let camera = camera.translation();
+    // The speed cap of the profile is as fast as a run gets for long.
+    let fast = map.movement.speed_soft_cap;
+
+    // The player's trail grows with the run, the ghosts' show their whole run.
+    let replays = player
+        .iter()
+        .map(|recorder| &recorder.0)
+        .chain(ghosts.iter().map(|playback| &playback.replay));
+    for replay in replays {
+        draw_trail(&mut gizmos, replay, fast, camera);
+    }
+}
+
+fn draw_trail(gizmos: &mut Gizmos, replay: &Replay, fast: f32, camera: Vec3) {
+    let mut strip = vec![];
+    for pair in replay.track.windows(2) {
+        let (from, to) = (pair[0].position, pair[1].position);
+        if from.distance(to) > MAX_STEP {
+            gizmos.linestrip_gradient(strip.drain(..));
+            continue;
+        }
+
+        let speed = (to - from).xz().length() * replay.tick_rate as f32;
+        let distance = to.distance(camera);
+        let fade = 1. - ((distance - FADE_START) / (FADE_END - FADE_START)).clamp(0., 1.);
+        strip.push((to + TRAIL_OFFSET, speed_color(speed, fast).with_a(fade)));
+    }
+    gizmos.linestrip_gradient(strip);
+}
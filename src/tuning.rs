@@ -0,0 +1,404 @@
+//! Developer panel to tune the player's movement live, toggled with F3.
+//!
+//! The values start out as the map's [`MovementProfile`] and can be exported as a new profile.
+//! While the panel is open, the map's profile file is reloaded into it whenever it changes on disk.
+
+use std::collections::VecDeque;
+#[cfg(not(target_arch = "wasm32"))]
+use std::{fs, time::SystemTime};
+
+use bevy::prelude::*;
+use bevy_egui::{
+    egui::{self, Color32, Pos2, Stroke},
+    EguiContexts,
+};
+use bevy_xpbd_3d::{prelude::LinearVelocity, PhysicsSet};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::profile::profile_path;
+use crate::{
+    character_controller::{AccelerationMultiplier, ControllerTunables},
+    map::Map,
+    physics::TICK_RATE,
+    profile::MovementProfile,
+    Player, State,
+};
+
+/// Seconds of history shown in the graphs.
+const HISTORY_SECONDS: f64 = 5.;
+
+pub struct TuningPlugin;
+
+impl Plugin for TuningPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MovementTuning>()
+            .add_systems(
+                FixedUpdate,
+                record_samples
+                    .after(PhysicsSet::Sync)
+                    .run_if(in_state(State::Playing)),
+            )
+            .add_systems(
+                Update,
+                (
+                    toggle_panel,
+                    reset_on_map_change,
+                    reload_profile_file,
+                    ui_tuning,
+                    apply_tuning,
+                )
+                    .chain()
+                    .run_if(resource_exists::<Map>()),
+            );
+    }
+}
+
+/// Marks a player driven with tuned values. Its run isn't saved as a highscore, ghost or split.
+/// Restarting the run clears it.
+#[derive(Component)]
+pub struct Tuned(MovementProfile);
+
+/// The player's speed and multiplier at one tick.
+struct Sample {
+    speed: f32,
+    multiplier: f32,
+}
+
+#[derive(Resource, Default)]
+pub struct MovementTuning {
+    open: bool,
+    /// The values the player is driven with.
+    pub profile: MovementProfile,
+    samples: VecDeque<Sample>,
+    export_name: String,
+    /// Result of the last reload or export.
+    status: String,
+    /// Modification time of the map's profile file when it was last read.
+    #[cfg(not(target_arch = "wasm32"))]
+    modified: Option<SystemTime>,
+}
+
+fn toggle_panel(keys: Res<Input<KeyCode>>, mut tuning: ResMut<MovementTuning>) {
+    if keys.just_pressed(KeyCode::F3) {
+        tuning.open = !tuning.open;
+    }
+}
+
+/// Starts over from the profile of a newly loaded map.
+pub fn reset_on_map_change(map: Res<Map>, mut tuning: ResMut<MovementTuning>) {
+    if !map.is_changed() {
+        return;
+    }
+
+    tuning.profile = map.movement.clone();
+    tuning.samples.clear();
+    tuning.export_name = format!("{}-tuned", map.profile);
+    tuning.status.clear();
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        tuning.modified = None;
+    }
+}
+
+fn reload_profile_file(map: Res<Map>, mut tuning: ResMut<MovementTuning>) {
+    #[cfg(target_arch = "wasm32")]
+    return;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        if !tuning.open {
+            return;
+        }
+        let Some(path) = profile_path(&map.profile) else {
+            return;
+        };
+        let Ok(modified) = fs::metadata(path).and_then(|metadata| metadata.modified()) else {
+            return;
+        };
+
+        let previous = tuning.modified.replace(modified);
+        if previous.is_none() || previous == Some(modified) {
+            return;
+        }
+
+        match MovementProfile::load(&map.profile) {
+            Ok(profile) => {
+                tuning.profile = profile;
+                tuning.status = format!("Reloaded profile {}", map.profile);
+            }
+            Err(e) => tuning.status = format!("Could not reload profile {}: {e}", map.profile),
+        }
+    }
+}
+
+/// Writes a profile to `profiles/{name}.json`, so maps can use it.
+#[cfg(not(target_arch = "wasm32"))]
+fn export_profile(profile: &MovementProfile, name: &str) -> Result<String, String> {
+    let path = profile_path(name).ok_or("the name must not contain '/', '\\' or '.'")?;
+    let json = serde_json::to_string_pretty(profile).map_err(|e| e.to_string())?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    fs::write(&path, json).map_err(|e| e.to_string())?;
+    Ok(path.display().to_string())
+}
+
+fn record_samples(
+    mut tuning: ResMut<MovementTuning>,
+    player: Query<(&LinearVelocity, &AccelerationMultiplier), With<Player>>,
+) {
+    let Ok((velocity, multiplier)) = player.get_single() else {
+        return;
+    };
+
+    tuning.samples.push_back(Sample {
+        speed: velocity.0.xz().length(),
+        multiplier: multiplier.0,
+    });
+    let capacity = (HISTORY_SECONDS * TICK_RATE) as usize;
+    while tuning.samples.len() > capacity {
+        tuning.samples.pop_front();
+    }
+}
+
+/// Draws the last [`HISTORY_SECONDS`] of a value as a line, scaled to its maximum.
+fn graph(ui: &mut egui::Ui, label: &str, values: Vec<f32>, color: Color32) {
+    let max = values.iter().copied().fold(1., f32::max);
+    let current = values.last().copied().unwrap_or_default();
+    ui.label(format!("{label}: {current:.2} (max {max:.2})"));
+
+    let (response, painter) = ui.allocate_painter(egui::vec2(300., 60.), egui::Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, 0., Color32::from_black_alpha(100));
+
+    let capacity = HISTORY_SECONDS * TICK_RATE;
+    // The newest sample is on the right edge.
+    let offset = capacity as usize - values.len();
+    let points = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            Pos2::new(
+                rect.left() + (offset + i) as f32 / capacity as f32 * rect.width(),
+                rect.bottom() - value / max * rect.height(),
+            )
+        })
+        .collect();
+    painter.add(egui::Shape::line(points, Stroke::new(1.5, color)));
+}
+
+fn ui_tuning(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    map: Res<Map>,
+    mut tuning: ResMut<MovementTuning>,
+    mut player: Query<(Entity, &mut AccelerationMultiplier), With<Player>>,
+) {
+    if !tuning.open {
+        return;
+    }
+    let tuning = &mut *tuning;
+
+    let mut open = true;
+    egui::Window::new("Movement tuning")
+        .open(&mut open)
+        .default_pos([20., 100.])
+        .show(contexts.ctx_mut(), |ui| {
+            let profile = &mut tuning.profile;
+            egui::Grid::new("tuning").show(ui, |ui| {
+                ui.label("Acceleration");
+                ui.add(
+                    egui::DragValue::new(&mut profile.acceleration)
+                        .clamp_range(0.1..=500.)
+                        .speed(0.5),
+                );
+                ui.end_row();
+
+                ui.label("Damping");
+                ui.add(egui::Slider::new(&mut profile.damping, 0.5..=1.0));
+                ui.end_row();
+
+                ui.label("Air damping");
+                ui.add(egui::Slider::new(&mut profile.air_damping, 0.5..=1.0));
+                ui.end_row();
+
+                ui.label("Air acceleration");
+                ui.add(
+                    egui::DragValue::new(&mut profile.air_acceleration)
+                        .clamp_range(0.1..=1000.)
+                        .speed(0.5),
+                );
+                ui.end_row();
+
+                ui.label("Air wish speed");
+                ui.add(
+                    egui::DragValue::new(&mut profile.air_wish_speed)
+                        .clamp_range(0.1..=100.)
+                        .speed(0.05),
+                );
+                ui.end_row();
+
+                ui.label("Speed soft cap");
+                ui.add(
+                    egui::DragValue::new(&mut profile.speed_soft_cap)
+                        .clamp_range(1.0..=500.)
+                        .speed(0.5),
+                );
+                ui.end_row();
+
+                ui.label("Over cap decay");
+                ui.add(egui::Slider::new(&mut profile.over_cap_decay, 0.5..=1.0));
+                ui.end_row();
+
+                ui.label("Jump impulse");
+                ui.add(
+                    egui::DragValue::new(&mut profile.jump_impulse)
+                        .clamp_range(0.1..=100.)
+                        .speed(0.1),
+                );
+                ui.end_row();
+
+                ui.label("Max slope angle");
+                ui.add(egui::Slider::new(&mut profile.max_slope_angle, 0.0..=90.0).suffix("°"));
+                ui.end_row();
+
+                ui.label("Gravity");
+                ui.add(
+                    egui::DragValue::new(&mut profile.gravity)
+                        .clamp_range(0.1..=200.)
+                        .speed(0.1),
+                );
+                ui.end_row();
+
+                ui.label("Jump multiplier bonus");
+                ui.add(
+                    egui::DragValue::new(&mut profile.jump_multiplier_bonus)
+                        .clamp_range(0.0..=10.)
+                        .speed(0.01),
+                );
+                ui.end_row();
+
+                ui.label("Air jumps");
+                ui.add(egui::DragValue::new(&mut profile.air_jumps).clamp_range(0..=10));
+                ui.end_row();
+
+                ui.label("Ground multiplier decay");
+                ui.add(egui::Slider::new(
+                    &mut profile.ground_multiplier_decay,
+                    0.9..=1.0,
+                ));
+                ui.end_row();
+
+                ui.label("Air multiplier decay");
+                ui.add(egui::Slider::new(
+                    &mut profile.air_multiplier_decay,
+                    0.9..=1.0,
+                ));
+                ui.end_row();
+
+                ui.label("Max multiplier");
+                ui.add(
+                    egui::DragValue::new(&mut profile.max_multiplier)
+                        .clamp_range(1.0..=100.)
+                        .speed(0.1),
+                );
+                ui.end_row();
+
+                ui.label("Jump cooldown");
+                ui.add(
+                    egui::DragValue::new(&mut profile.jump_cooldown_ms)
+                        .clamp_range(0..=2000)
+                        .suffix(" ms"),
+                );
+                ui.end_row();
+
+                if let Ok((entity, mut multiplier)) = player.get_single_mut() {
+                    ui.label("Current multiplier");
+                    let max = profile.max_multiplier;
+                    if ui
+                        .add(egui::Slider::new(&mut multiplier.0, 1.0..=max))
+                        .changed()
+                    {
+                        commands.entity(entity).insert(Tuned(profile.clone()));
+                    }
+                    ui.end_row();
+                }
+            });
+
+            graph(
+                ui,
+                "Speed",
+                tuning.samples.iter().map(|sample| sample.speed).collect(),
+                Color32::LIGHT_BLUE,
+            );
+            graph(
+                ui,
+                "Multiplier",
+                tuning
+                    .samples
+                    .iter()
+                    .map(|sample| sample.multiplier)
+                    .collect(),
+                Color32::YELLOW,
+            );
+
+            ui.horizontal(|ui| {
+                if ui.button("Reset to map profile").clicked() {
+                    tuning.profile = map.movement.clone();
+                }
+
+                #[cfg(not(target_arch = "wasm32"))]
+                if ui.button("Reload file").clicked() {
+                    match MovementProfile::load(&map.profile) {
+                        Ok(profile) => {
+                            tuning.profile = profile;
+                            tuning.status = format!("Reloaded profile {}", map.profile);
+                        }
+                        Err(e) => {
+                            tuning.status = format!("Could not reload profile {}: {e}", map.profile)
+                        }
+                    }
+                }
+            });
+
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.horizontal(|ui| {
+                ui.label("Export as");
+                ui.text_edit_singleline(&mut tuning.export_name);
+                if ui.button("Export").clicked() {
+                    tuning.status = match export_profile(&tuning.profile, &tuning.export_name) {
+                        Ok(path) => format!("Exported to {path}"),
+                        Err(e) => format!("Could not export: {e}"),
+                    };
+                }
+            });
+
+            if !tuning.status.is_empty() {
+                ui.label(&tuning.status);
+            }
+            ui.label("Tuned runs aren't saved. Restart to drive the map's profile again.");
+        });
+
+    tuning.open = open;
+}
+
+/// Applies the panel's values to the player when they differ from what it is driven with.
+/// Ghosts keep the map's profile, their replays were recorded with it.
+pub fn apply_tuning(
+    mut commands: Commands,
+    map: Res<Map>,
+    tuning: Res<MovementTuning>,
+    mut player: Query<(Entity, ControllerTunables, Option<&Tuned>), With<Player>>,
+) {
+    let Ok((entity, mut tunables, tuned)) = player.get_single_mut() else {
+        return;
+    };
+
+    let current = tuned.map_or(&map.movement, |tuned| &tuned.0);
+    if *current != tuning.profile {
+        tunables.apply(&tuning.profile);
+        commands
+            .entity(entity)
+            .insert(Tuned(tuning.profile.clone()));
+    }
+}
@@ -1,16 +1,21 @@
 use bevy::{app::AppExit, prelude::*, window::CursorGrabMode};
 use bevy_egui::{
-    egui::{self, Color32, FontId, Frame, Margin, TextStyle, Visuals},
+    egui::{self, Align2, Color32, FontId, Frame, Margin, RichText, TextStyle, Visuals},
     EguiContexts,
 };
 use instant::Duration;
 
 use crate::{
+    bindings::{Actions, InputAction},
+    character_controller::PendingInput,
     events::StateEvents,
-    ghost::GhostOneshots,
+    leaderboard::PlayerName,
     map::Map,
-    timing::{Countdown, MapDuration},
-    MapEntityMarker, Maps, State, StateOneshots,
+    settings::{show_settings, Settings},
+    splits::{show_breakdown, FinishedRun},
+    storage::StorageErrors,
+    timing::{format_time, Countdown, MapDuration},
+    MapEntityMarker, Maps, Player, State, StateOneshots,
 };
 
 pub fn setup_ui(mut contexts: EguiContexts) {
@@ -43,15 +48,28 @@ pub fn setup_ui(mut contexts: EguiContexts) {
     ctx.set_style(style);
 }
 
+/// Lists storage errors until they are dismissed.
+pub fn show_storage_errors(ui: &mut egui::Ui, errors: &mut StorageErrors) {
+    if errors.0.is_empty() {
+        return;
+    }
+
+    for error in &errors.0 {
+        ui.colored_label(Color32::DARK_RED, error.to_string());
+    }
+    if ui.button("Dismiss").clicked() {
+        errors.0.clear();
+    }
+}
+
 pub fn ui_mainscreen(
     mut commands: Commands,
     mut contexts: EguiContexts,
     mut exit: EventWriter<AppExit>,
     mut state: ResMut<NextState<State>>,
-    mut windows: Query<&mut Window>,
     maps: Res<Maps>,
-    oneshots: Res<StateOneshots>,
-    ghost_oneshots: Res<GhostOneshots>,
+    mut name: ResMut<PlayerName>,
+    mut errors: ResMut<StorageErrors>,
 ) {
     let ctx = contexts.ctx_mut();
     egui::Area::new("forg").show(ctx, |ui| {
@@ -67,21 +85,41 @@ pub fn ui_mainscreen(
                     ui.collapsing("Load map", |ui| {
                         for map in &maps.maps {
                             if ui.button(map).clicked() {
-                                commands.run_system(oneshots.unload);
-                                let mut window = windows.single_mut();
-                                window.cursor.grab_mode = CursorGrabMode::Locked;
-                                window.cursor.visible = false;
-                                commands.insert_resource(Map::from(map.as_str()));
-                                commands.run_system(oneshots.load_map);
-                                commands.run_system(ghost_oneshots.load);
-                                state.set(State::Playing);
+                                // The map was valid at startup, but the file could have changed since.
+                                let map = match Map::load(map) {
+                                    Ok(map) => map,
+                                    Err(e) => {
+                                        println!("Could not load map {map}: {e}");
+                                        continue;
+                                    }
+                                };
+                                commands.insert_resource(map);
+                                state.set(State::GhostSelect);
                             }
                         }
                     });
                 });
+                if !maps.broken.is_empty() {
+                    ui.collapsing(format!("{} maps failed to load", maps.broken.len()), |ui| {
+                        for (name, error) in &maps.broken {
+                            ui.colored_label(Color32::DARK_RED, format!("{name}: {error}"));
+                        }
+                    });
+                }
+                show_storage_errors(ui, &mut errors);
+                ui.horizontal(|ui| {
+                    ui.label("Name:");
+                    ui.text_edit_singleline(&mut name.0);
+                });
                 if ui.button("Leaderboard").clicked() {
                     state.set(State::Leaderboard);
                 }
+                if ui.button("Controls").clicked() {
+                    state.set(State::Controls);
+                }
+                if ui.button("Settings").clicked() {
+                    state.set(State::Settings);
+                }
                 if ui.button("Quit").clicked() {
                     exit.send(AppExit);
                 }
@@ -94,11 +132,11 @@ pub fn ui_finish(
     mut contexts: EguiContexts,
     mut state: ResMut<NextState<State>>,
     mut commands: Commands,
-    mut windows: Query<&mut Window>,
     mut ew: EventWriter<StateEvents>,
     query: Query<&MapDuration>,
     oneshots: Res<StateOneshots>,
-    ghost_oneshots: Res<GhostOneshots>,
+    finished: Option<Res<FinishedRun>>,
+    mut errors: ResMut<StorageErrors>,
 ) {
     let ctx = contexts.ctx_mut();
     egui::Area::new("forg").show(ctx, |ui| {
@@ -114,17 +152,24 @@ pub fn ui_finish(
 
                 let duration = query.single();
 
-                ui.label(format!("Finished in {}", duration.elapsed().as_secs_f32()));
+                ui.label(format!("Finished in {}", format_time(duration.elapsed())));
+
+                let laps = duration.lap_times();
+                if laps.len() > 1 {
+                    for (i, lap) in laps.iter().enumerate() {
+                        ui.label(format!("Lap {}: {}", i + 1, format_time(*lap)));
+                    }
+                }
+
+                if let Some(finished) = &finished {
+                    show_breakdown(ui, finished);
+                }
+
+                show_storage_errors(ui, &mut errors);
 
                 ui.horizontal(|ui| {
                     if ui.button("Reset").clicked() {
-                        commands.run_system(oneshots.unload);
-                        let mut window = windows.single_mut();
-                        window.cursor.grab_mode = CursorGrabMode::Locked;
-                        window.cursor.visible = false;
-                        commands.run_system(oneshots.load_map);
-                        commands.run_system(ghost_oneshots.load);
-                        state.set(State::Playing);
+                        commands.run_system(oneshots.restart);
                     }
                     if ui.button("Back to menu").clicked() {
                         state.set(State::Mainscreen);
@@ -139,24 +184,139 @@ pub fn ui_finish(
     });
 }
 
-pub fn to_main_menu(
+/// Shows the current lap on maps with more than one.
+pub fn display_lap(
+    mut contexts: EguiContexts,
+    map: Res<Map>,
+    player: Query<&MapDuration, With<Player>>,
+) {
+    if map.laps <= 1 {
+        return;
+    }
+    let lap = player
+        .get_single()
+        .map_or(0, |duration| duration.laps_completed())
+        + 1;
+
+    egui::Area::new("lap")
+        .anchor(Align2::RIGHT_TOP, [-20., 20.])
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(
+                RichText::new(format!("Lap {}/{}", lap.min(map.laps as usize), map.laps)).size(30.),
+            );
+        });
+}
+
+/// Starts the map over on [`InputAction::Restart`].
+pub fn restart_run(mut commands: Commands, actions: Actions, oneshots: Res<StateOneshots>) {
+    if actions.just_pressed(InputAction::Restart) {
+        commands.run_system(oneshots.restart);
+    }
+}
+
+pub fn pause_run(actions: Actions, mut state: ResMut<NextState<State>>) {
+    if actions.just_pressed(InputAction::Pause) {
+        state.set(State::Paused);
+    }
+}
+
+/// Freezes virtual time, which stops the fixed tick and with it physics, the countdown and the run's clock.
+pub fn on_pause(mut time: ResMut<Time<Virtual>>, mut windows: Query<&mut Window>) {
+    time.pause();
+
+    let mut window = windows.single_mut();
+    window.cursor.grab_mode = CursorGrabMode::None;
+    window.cursor.visible = true;
+}
+
+pub fn on_resume(mut time: ResMut<Time<Virtual>>) {
+    time.unpause();
+}
+
+pub fn ui_paused(
     mut commands: Commands,
-    oneshots: Res<StateOneshots>,
-    keyboard_input: Res<Input<KeyCode>>,
-    mut state: ResMut<NextState<crate::State>>,
-    mut ew: EventWriter<StateEvents>,
+    mut contexts: EguiContexts,
+    actions: Actions,
+    mut state: ResMut<NextState<State>>,
     mut windows: Query<&mut Window>,
+    mut ew: EventWriter<StateEvents>,
+    mut pending: ResMut<PendingInput>,
+    mut settings: ResMut<Settings>,
+    mut errors: ResMut<StorageErrors>,
+    oneshots: Res<StateOneshots>,
+    mut settings_open: Local<bool>,
 ) {
-    if keyboard_input.just_pressed(KeyCode::Escape) {
-        let mut window = windows.single_mut();
-        state.set(crate::State::Mainscreen);
+    let mut resume = actions.just_pressed(InputAction::Pause);
+    let mut quit = false;
+
+    let ctx = contexts.ctx_mut();
+    egui::Area::new("paused").show(ctx, |ui| {
+        Frame {
+            outer_margin: Margin::symmetric(300., 0.),
+            inner_margin: Margin::same(20.),
+            fill: Color32::from_rgba_unmultiplied(255, 255, 255, 150),
+            ..Default::default()
+        }
+        .show(ui, |ui| {
+            ui.vertical_centered(|ui| {
+                ui.heading("Paused");
+
+                if *settings_open {
+                    let mut edited = settings.clone();
+                    show_settings(ui, &mut edited);
+                    if edited != *settings {
+                        *settings = edited;
+                    }
+                    if ui.button("Done").clicked() {
+                        settings.save(&mut errors);
+                        *settings_open = false;
+                    }
+                    show_storage_errors(ui, &mut errors);
+                    return;
+                }
+
+                if ui.button("Resume").clicked() {
+                    resume = true;
+                }
+                if ui.button("Restart").clicked() {
+                    commands.run_system(oneshots.restart);
+                }
+                if ui.button("Restart from checkpoint").clicked() {
+                    pending.request_reset();
+                    resume = true;
+                }
+                if ui.button("Settings").clicked() {
+                    *settings_open = true;
+                }
+                if ui.button("Quit to menu").clicked() {
+                    quit = true;
+                }
+            })
+        })
+    });
+
+    if *settings_open && (resume || quit) {
+        settings.save(&mut errors);
+        *settings_open = false;
+    }
+
+    if quit {
+        state.set(State::Mainscreen);
         commands.run_system(oneshots.unload);
         ew.send(StateEvents::LoadMainscreen);
-        window.cursor.grab_mode = CursorGrabMode::None;
-        window.cursor.visible = true;
+        commands.remove_resource::<Map>();
+    } else if resume {
+        let mut window = windows.single_mut();
+        window.cursor.grab_mode = CursorGrabMode::Locked;
+        window.cursor.visible = false;
+        state.set(State::Playing);
     }
 }
 
+/// Root of the countdown text, see [`spawn_countdown_display`].
+#[derive(Component)]
+pub struct CountdownDisplay;
+
 pub fn spawn_countdown_display(mut commands: Commands<'_, '_>) {
     let text_style = bevy::text::TextStyle {
         font_size: 60.,
@@ -172,6 +332,7 @@ pub fn spawn_countdown_display(mut commands: Commands<'_, '_>) {
                 },
                 ..Default::default()
             },
+            CountdownDisplay,
             MapEntityMarker,
         ))
         .with_children(|commands| {
@@ -0,0 +1,435 @@
+//! Watching replays from the leaderboard: the runs follow their recorded tracks side by side
+//! and can be paused, slowed down, sped up and scrubbed through. The camera follows a run,
+//! flies freely or looks down on the map.
+
+use std::f32::consts::PI;
+
+use bevy::{input::mouse::MouseMotion, prelude::*, window::CursorGrabMode};
+use bevy_egui::{
+    egui::{self, Align2, Color32, Frame, Margin, RichText},
+    EguiContexts,
+};
+use bevy_xpbd_3d::prelude::Position;
+use instant::Duration;
+
+use crate::{
+    assets::AssetHandles,
+    bindings::{Actions, InputAction},
+    camera::{CameraLeash, IgnoreMouseInput, LeashedCamera, RADIANS_PER_DOT},
+    ghost::{ghost_color, spawn_ghost, to_color32, Ghost},
+    map::Map,
+    physics::InterpolatedPosition,
+    player::{camera_ray, start_transform},
+    replay::{follow_tracks, Replay, TrackPlayback},
+    settings::Settings,
+    splits::format_delta,
+    timing::format_time,
+    State, StateOneshots,
+};
+
+/// Playback speeds to pick from.
+const SPEEDS: [f32; 6] = [0.1, 0.25, 0.5, 1., 2., 4.];
+
+/// Speed of the free camera in metres per second, shift flies faster.
+const FLY_SPEED: f32 = 20.;
+const FAST_FLY_MULTIPLIER: f32 = 4.;
+
+/// Trails are drawn this far above the track, so they aren't hidden in the ground.
+const TRAIL_OFFSET: Vec3 = Vec3::new(0., 0.2, 0.);
+
+pub struct ViewerPlugin;
+
+impl Plugin for ViewerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (
+                advance_viewer.before(follow_tracks),
+                (leash_followed_run, fly_camera, top_down_camera)
+                    .chain()
+                    .after(follow_tracks),
+                draw_trails.after(follow_tracks),
+                ui_viewer,
+            )
+                .run_if(in_state(State::Replay)),
+        );
+    }
+}
+
+/// Replays picked to be watched with the names shown for them,
+/// spawned by [`watch_replay`] once the map is loaded.
+#[derive(Resource)]
+pub struct WatchedReplays(pub Vec<(String, Replay)>);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ViewerCamera {
+    /// Leashed to the followed run like during a run.
+    Follow,
+    /// Flown with the movement keys, looking around while the right mouse button is held.
+    /// Q and E fly down and up.
+    FreeFly,
+    /// High above the followed run, looking straight down.
+    TopDown,
+}
+
+impl ViewerCamera {
+    const ALL: [ViewerCamera; 3] = [
+        ViewerCamera::Follow,
+        ViewerCamera::FreeFly,
+        ViewerCamera::TopDown,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            ViewerCamera::Follow => "Follow",
+            ViewerCamera::FreeFly => "Free",
+            ViewerCamera::TopDown => "Top down",
+        }
+    }
+}
+
+/// Playback of the replays in the viewer.
+#[derive(Resource)]
+pub struct ReplayViewer {
+    pub playing: bool,
+    pub speed: f32,
+    pub time: Duration,
+    /// Duration of the longest run.
+    pub duration: Duration,
+    /// The watched runs, in the order they were picked.
+    pub runs: Vec<Entity>,
+    /// Index into `runs` of the run the camera follows.
+    /// Time differences of the others are measured against it.
+    pub followed: usize,
+    pub camera: ViewerCamera,
+    /// Height of the top down camera above the followed run.
+    pub top_down_height: f32,
+    pub show_trails: bool,
+}
+
+/// Spawns the [`WatchedReplays`] with the camera following the first, run after `load_map`.
+pub fn watch_replay(
+    mut commands: Commands,
+    watched: Option<Res<WatchedReplays>>,
+    map: Res<Map>,
+    handles: Res<AssetHandles>,
+    mut windows: Query<&mut Window>,
+    mut cameras: Query<&mut IgnoreMouseInput>,
+) {
+    let Some(watched) = watched else {
+        return;
+    };
+    let start = start_transform(&map).translation;
+
+    let mut runs = vec![];
+    let mut duration = Duration::ZERO;
+    for (index, (name, replay)) in watched.0.iter().enumerate() {
+        duration = duration.max(replay.time());
+        let ghost = Ghost {
+            name: name.clone(),
+            color: ghost_color(index),
+        };
+        let run = spawn_ghost(&mut commands, &map, &handles, replay.clone(), ghost);
+        // The camera leash casts its ray from the position.
+        commands.entity(run).insert((
+            Position(start),
+            InterpolatedPosition::new(start),
+            camera_ray(),
+        ));
+        runs.push(run);
+    }
+
+    commands.insert_resource(ReplayViewer {
+        playing: true,
+        speed: 1.,
+        time: Duration::ZERO,
+        duration,
+        runs,
+        followed: 0,
+        camera: ViewerCamera::Follow,
+        top_down_height: 60.,
+        show_trails: true,
+    });
+    commands.remove_resource::<WatchedReplays>();
+
+    // The controls need the cursor, the camera lock toggle frees the camera.
+    for mut window in &mut windows {
+        window.cursor.grab_mode = CursorGrabMode::None;
+        window.cursor.visible = true;
+    }
+    for mut ignore in &mut cameras {
+        ignore.0 = true;
+    }
+}
+
+fn advance_viewer(
+    time: Res<Time>,
+    mut viewer: ResMut<ReplayViewer>,
+    mut tracks: Query<&mut TrackPlayback>,
+) {
+    if viewer.playing {
+        viewer.time += time.delta().mul_f32(viewer.speed);
+        if viewer.time >= viewer.duration {
+            viewer.time = viewer.duration;
+            viewer.playing = false;
+        }
+    }
+
+    for mut track in &mut tracks {
+        track.time = viewer.time;
+    }
+}
+
+/// Only the followed run has the [`CameraLeash`], and only in [`ViewerCamera::Follow`].
+fn leash_followed_run(
+    mut commands: Commands,
+    viewer: Res<ReplayViewer>,
+    leashed: Query<Entity, With<CameraLeash>>,
+) {
+    let followed = viewer.runs.get(viewer.followed).copied();
+    let leash = followed.filter(|_| viewer.camera == ViewerCamera::Follow);
+
+    for entity in &leashed {
+        if Some(entity) != leash {
+            commands.entity(entity).remove::<CameraLeash>();
+        }
+    }
+    if let Some(leash) = leash {
+        if !leashed.contains(leash) {
+            commands.entity(leash).insert(CameraLeash);
+        }
+    }
+}
+
+fn fly_camera(
+    viewer: Res<ReplayViewer>,
+    actions: Actions,
+    keys: Res<Input<KeyCode>>,
+    mouse: Res<Input<MouseButton>>,
+    mut mouse_events: EventReader<MouseMotion>,
+    time: Res<Time>,
+    settings: Res<Settings>,
+    mut cameras: Query<&mut Transform, With<LeashedCamera>>,
+) {
+    let mut mouse_delta = Vec2::ZERO;
+    for mouse_event in mouse_events.read() {
+        mouse_delta += mouse_event.delta;
+    }
+    if viewer.camera != ViewerCamera::FreeFly {
+        return;
+    }
+
+    // Without the right mouse button, the mouse is left to the controls.
+    if !mouse.pressed(MouseButton::Right) {
+        mouse_delta = Vec2::ZERO;
+    }
+    let mut stick = actions.look(time.delta_seconds());
+    if settings.invert_y {
+        mouse_delta.y = -mouse_delta.y;
+        stick.y = -stick.y;
+    }
+    let sensitivity = settings.mouse_sensitivity;
+    let yaw = mouse_delta.x * RADIANS_PER_DOT * sensitivity + stick.x;
+    let pitch = -mouse_delta.y * RADIANS_PER_DOT * sensitivity + stick.y;
+
+    let movement = actions.movement();
+    let vertical = keys.pressed(KeyCode::E) as i8 - keys.pressed(KeyCode::Q) as i8;
+    let mut speed = FLY_SPEED;
+    if keys.pressed(KeyCode::ShiftLeft) {
+        speed *= FAST_FLY_MULTIPLIER;
+    }
+
+    for mut transform in &mut cameras {
+        let (camera_yaw, camera_pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+        transform.rotation = Quat::from_euler(
+            EulerRot::YXZ,
+            camera_yaw - yaw,
+            (camera_pitch + pitch).clamp(-PI / 2., PI / 2.),
+            0.,
+        );
+
+        let direction = transform.forward() * movement.y
+            + transform.right() * movement.x
+            + Vec3::Y * vertical as f32;
+        transform.translation += direction * speed * time.delta_seconds();
+    }
+}
+
+fn top_down_camera(
+    viewer: Res<ReplayViewer>,
+    runs: Query<&InterpolatedPosition>,
+    mut cameras: Query<&mut Transform, With<LeashedCamera>>,
+) {
+    if viewer.camera != ViewerCamera::TopDown {
+        return;
+    }
+    let Some(run) = viewer
+        .runs
+        .get(viewer.followed)
+        .and_then(|&run| runs.get(run).ok())
+    else {
+        return;
+    };
+
+    for mut transform in &mut cameras {
+        *transform = Transform::from_translation(run.rendered + Vec3::Y * viewer.top_down_height)
+            .looking_at(run.rendered, Vec3::NEG_Z);
+    }
+}
+
+/// Draws the whole path of each run in its colour.
+fn draw_trails(
+    mut gizmos: Gizmos,
+    viewer: Res<ReplayViewer>,
+    runs: Query<(&TrackPlayback, &Ghost)>,
+) {
+    if !viewer.show_trails {
+        return;
+    }
+
+    for (playback, ghost) in &runs {
+        let positions = playback
+            .replay
+            .track
+            .iter()
+            .map(|s| s.position + TRAIL_OFFSET);
+        gizmos.linestrip(positions, ghost.color);
+    }
+}
+
+/// How far the run at `position` is behind the followed run, in seconds.
+/// Negative when it's ahead.
+fn time_difference(followed: &Replay, position: Vec3, time: Duration) -> Option<f32> {
+    let passed = followed.time_at(position)?;
+    Some(time.as_secs_f32() - passed.as_secs_f32())
+}
+
+fn ui_viewer(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    actions: Actions,
+    mut viewer: ResMut<ReplayViewer>,
+    mut state: ResMut<NextState<State>>,
+    oneshots: Res<StateOneshots>,
+    runs: Query<(&TrackPlayback, &Ghost, &Transform)>,
+) {
+    let mut back = actions.just_pressed(InputAction::Pause);
+    if actions.just_pressed(InputAction::Jump) {
+        viewer.playing = !viewer.playing;
+    }
+
+    let followed = viewer
+        .runs
+        .get(viewer.followed)
+        .and_then(|&run| runs.get(run).ok())
+        .map(|(playback, ..)| &playback.replay);
+
+    let ctx = contexts.ctx_mut();
+    egui::Area::new("replay runs")
+        .anchor(Align2::RIGHT_TOP, [-20., 20.])
+        .show(ctx, |ui| {
+            Frame {
+                inner_margin: Margin::same(20.),
+                fill: Color32::from_rgba_unmultiplied(255, 255, 255, 150),
+                ..Default::default()
+            }
+            .show(ui, |ui| {
+                egui::Grid::new("replay runs grid").show(ui, |ui| {
+                    for index in 0..viewer.runs.len() {
+                        let Ok((playback, ghost, transform)) = runs.get(viewer.runs[index]) else {
+                            continue;
+                        };
+
+                        let name = RichText::new(&ghost.name)
+                            .color(to_color32(ghost.color))
+                            .strong();
+                        ui.radio_value(&mut viewer.followed, index, name);
+
+                        // Finished runs show their time, the others how far they are behind
+                        // the followed run where they are now.
+                        let time = playback.replay.time();
+                        if viewer.time >= time {
+                            ui.label(format_time(time));
+                        } else if let Some(delta) = followed
+                            .filter(|_| index != viewer.followed)
+                            .and_then(|followed| {
+                                time_difference(followed, transform.translation, viewer.time)
+                            })
+                        {
+                            let (delta, color) = format_delta(delta);
+                            ui.colored_label(color, delta);
+                        } else {
+                            ui.label("");
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+
+    egui::Area::new("replay viewer")
+        .anchor(Align2::CENTER_BOTTOM, [0., -20.])
+        .show(ctx, |ui| {
+            Frame {
+                inner_margin: Margin::same(20.),
+                fill: Color32::from_rgba_unmultiplied(255, 255, 255, 150),
+                ..Default::default()
+            }
+            .show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    let label = if viewer.playing { "Pause" } else { "Play" };
+                    if ui.button(label).clicked() {
+                        // Playing again from the end starts over.
+                        if !viewer.playing && viewer.time >= viewer.duration {
+                            viewer.time = Duration::ZERO;
+                        }
+                        viewer.playing = !viewer.playing;
+                    }
+
+                    let mut seconds = viewer.time.as_secs_f64();
+                    let duration = viewer.duration.as_secs_f64();
+                    ui.spacing_mut().slider_width = 400.;
+                    let timeline =
+                        ui.add(egui::Slider::new(&mut seconds, 0.0..=duration).show_value(false));
+                    if timeline.changed() {
+                        viewer.time = Duration::from_secs_f64(seconds);
+                    }
+                    ui.label(format!(
+                        "{} / {}",
+                        format_time(viewer.time),
+                        format_time(viewer.duration)
+                    ));
+
+                    egui::ComboBox::from_id_source("replay speed")
+                        .selected_text(format!("{}x", viewer.speed))
+                        .show_ui(ui, |ui| {
+                            for speed in SPEEDS {
+                                ui.selectable_value(&mut viewer.speed, speed, format!("{speed}x"));
+                            }
+                        });
+
+                    back |= ui.button("Back").clicked();
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("Camera:");
+                    for camera in ViewerCamera::ALL {
+                        ui.selectable_value(&mut viewer.camera, camera, camera.label());
+                    }
+                    if viewer.camera == ViewerCamera::TopDown {
+                        ui.add(
+                            egui::Slider::new(&mut viewer.top_down_height, 10.0..=300.0)
+                                .text("Height"),
+                        );
+                    }
+                    ui.checkbox(&mut viewer.show_trails, "Trails");
+                });
+            });
+        });
+
+    if back {
+        commands.run_system(oneshots.unload);
+        commands.remove_resource::<ReplayViewer>();
+        state.set(State::Leaderboard);
+    }
+}
@@ -0,0 +1,493 @@
+use std::{f32::consts::PI, time::Duration};
+
+use bevy::prelude::{Quat, Vec3};
+use gottagofaster::headless::{
+    Checkpoint, DecodeError, HeadlessGame, Map, MapError, MovementAction, MovementProfile, Replay,
+    Surface, TickInput,
+};
+
+fn winter() -> HeadlessGame {
+    HeadlessGame::with_map(Map::load("winter").unwrap()).unwrap()
+}
+
+fn forward(yaw: f32) -> TickInput {
+    TickInput {
+        yaw,
+        actions: vec![MovementAction::Move(Vec3::Z)],
+    }
+}
+
+/// Input moving towards `direction` in world space, whatever the camera yaw.
+fn towards(yaw: f32, direction: Vec3) -> TickInput {
+    let local = Quat::from_rotation_y(-(yaw + PI)) * direction;
+    TickInput {
+        yaw,
+        actions: vec![MovementAction::Move(Vec3::new(local.x, 0., -local.z))],
+    }
+}
+
+fn horizontal_speed(velocity: Vec3) -> f32 {
+    Vec3::new(velocity.x, 0., velocity.z).length()
+}
+
+#[test]
+fn same_input_gives_same_run() {
+    let script: Vec<TickInput> = (0..300)
+        .map(|tick| {
+            let mut input = forward(tick as f32 * 0.01);
+            if tick % 45 == 0 {
+                input.actions.push(MovementAction::Jump);
+            }
+            input
+        })
+        .collect();
+
+    let mut first = winter();
+    let mut second = winter();
+    first.run_script(&script);
+    second.run_script(&script);
+
+    assert_eq!(first.player_position(), second.player_position());
+}
+
+#[test]
+fn forward_moves_in_start_direction() {
+    let mut game = winter();
+    let start = game.player_position();
+
+    game.hold(&forward(game.start_yaw()), 2.);
+
+    let moved = game.player_position() - start;
+    let facing = Quat::from_rotation_y(game.start_yaw()) * Vec3::Z;
+    assert!(moved.dot(facing) > 10., "moved {moved}");
+}
+
+#[test]
+fn standing_still_reaches_nothing() {
+    let mut game = winter();
+
+    game.hold(&TickInput::default(), 3.);
+
+    assert_eq!(game.checkpoints_reached(), 0);
+    assert!(!game.is_finished());
+}
+
+/// Winter with checkpoints and the goal moved onto a straight line from the start,
+/// `distances` ahead of it in the order they are listed.
+fn winter_straight(distances: &[f32], goal: f32) -> Map {
+    let mut map = Map::load("winter").unwrap();
+    let facing = Quat::from_rotation_y(map.start_rotation.to_radians()) * Vec3::Z;
+    map.checkpoints = distances
+        .iter()
+        .map(|distance| Checkpoint {
+            pos: map.start_pos + facing * *distance,
+            rot: map.start_rotation,
+        })
+        .collect();
+    map.end_pos = map.start_pos + facing * goal;
+    map.end_rotation = map.start_rotation;
+    map
+}
+
+#[test]
+fn running_forward_reaches_checkpoints() {
+    let mut game = HeadlessGame::with_map(winter_straight(&[6., 12.], 200.)).unwrap();
+
+    game.hold(&forward(game.start_yaw()), 3.);
+
+    assert_eq!(game.checkpoints_reached(), 2);
+}
+
+#[test]
+fn ordered_checkpoints_skip_the_ones_ahead() {
+    // The second checkpoint is passed first, it only counts after the first one.
+    let mut map = winter_straight(&[12., 6.], 200.);
+    map.ordered_checkpoints = true;
+    let mut game = HeadlessGame::with_map(map).unwrap();
+
+    game.hold(&forward(game.start_yaw()), 3.);
+
+    assert_eq!(game.checkpoints_reached(), 1);
+}
+
+#[test]
+fn goal_completes_a_lap_until_the_last_one() {
+    let mut map = winter_straight(&[6.], 15.);
+    map.laps = 2;
+    let mut game = HeadlessGame::with_map(map).unwrap();
+
+    game.hold(&forward(game.start_yaw()), 3.);
+
+    assert_eq!(game.laps_completed(), 1);
+    assert!(!game.is_finished());
+    // The next lap needs the checkpoints again.
+    assert_eq!(game.checkpoints_reached(), 0);
+}
+
+#[test]
+fn goal_after_all_checkpoints_finishes_the_run() {
+    let mut game = HeadlessGame::with_map(winter_straight(&[6.], 15.)).unwrap();
+
+    game.hold(&forward(game.start_yaw()), 3.);
+
+    assert!(game.is_finished());
+    assert_eq!(game.laps_completed(), 1);
+}
+
+#[test]
+fn air_strafing_gains_speed() {
+    // Runs up, jumps and is off the ground when the script ends.
+    fn take_off() -> HeadlessGame {
+        let mut game = winter();
+        let yaw = game.start_yaw();
+        game.hold(&forward(yaw), 1.);
+        let mut jump = forward(yaw);
+        jump.actions.push(MovementAction::Jump);
+        game.step(&jump);
+        game.hold(
+            &TickInput {
+                yaw,
+                actions: vec![],
+            },
+            0.1,
+        );
+        game
+    }
+
+    let mut coasting = take_off();
+    let mut strafing = take_off();
+    let yaw = strafing.start_yaw();
+    let takeoff = horizontal_speed(strafing.player_velocity());
+
+    coasting.hold(
+        &TickInput {
+            yaw,
+            actions: vec![],
+        },
+        0.3,
+    );
+    // Strafing sideways to the velocity while turning along with it.
+    for _ in 0..18 {
+        let velocity = strafing.player_velocity();
+        let sideways = Vec3::Y
+            .cross(Vec3::new(velocity.x, 0., velocity.z))
+            .normalize();
+        strafing.step(&towards(yaw, sideways));
+    }
+
+    let coasted = horizontal_speed(coasting.player_velocity());
+    let strafed = horizontal_speed(strafing.player_velocity());
+    assert!(
+        coasted < takeoff,
+        "coasted {coasted}, took off with {takeoff}"
+    );
+    assert!(
+        strafed > takeoff,
+        "strafed {strafed}, took off with {takeoff}"
+    );
+}
+
+#[test]
+fn speed_soft_cap_holds() {
+    let mut map = Map::load("winter").unwrap();
+    map.movement.speed_soft_cap = 5.;
+    map.movement.over_cap_decay = 0.5;
+    let mut game = HeadlessGame::with_map(map).unwrap();
+
+    // Uncapped, running for this long is several times as fast.
+    game.hold(&forward(game.start_yaw()), 3.);
+
+    let speed = horizontal_speed(game.player_velocity());
+    assert!(speed < 6., "speed {speed}");
+}
+
+#[test]
+fn ice_keeps_more_speed_than_ground() {
+    let coast = |game: &mut HeadlessGame| {
+        let yaw = game.start_yaw();
+        game.hold(&forward(yaw), 1.);
+        game.hold(
+            &TickInput {
+                yaw,
+                actions: vec![],
+            },
+            0.5,
+        );
+        horizontal_speed(game.player_velocity())
+    };
+
+    let ground = coast(&mut winter());
+    let mut game = winter();
+    game.cover_ground(Surface::Ice);
+    let ice = coast(&mut game);
+
+    assert!(ice > ground, "ice {ice}, ground {ground}");
+}
+
+#[test]
+fn ghosts_go_back_on_reset() {
+    let mut game = HeadlessGame::with_map(winter_straight(&[6.], 200.)).unwrap();
+    let yaw = game.start_yaw();
+    let ghost = game.add_ghost(Replay::new(&Map::load("winter").unwrap()));
+
+    let mut crossed = None;
+    for _ in 0..120 {
+        game.step(&forward(yaw));
+        if crossed.is_none() && game.checkpoints_reached() == 1 {
+            crossed = Some(game.run_time());
+        }
+    }
+    let crossed = crossed.expect("The checkpoint is on the way.");
+    let before = game.ghost_time(ghost);
+
+    game.step(&TickInput {
+        yaw,
+        actions: vec![MovementAction::Reset],
+    });
+
+    // Back to when the player last was inside the checkpoint.
+    let after = game.ghost_time(ghost);
+    assert!(
+        after >= crossed,
+        "{after:?} before the checkpoint {crossed:?}"
+    );
+    assert!(
+        after + Duration::from_millis(200) < before,
+        "{after:?} instead of before {before:?}"
+    );
+    // From there on the ghost keeps pace with the clock again.
+    game.hold(&TickInput::default(), 0.5);
+    let elapsed = (game.ghost_time(ghost) - after).as_secs_f64();
+    assert!((elapsed - 0.5).abs() < 0.01, "elapsed {elapsed}");
+}
+
+#[test]
+fn finish_is_timed_between_ticks() {
+    // The checkpoint is reached right at the start.
+    let mut game = HeadlessGame::with_map(winter_straight(&[0.], 15.)).unwrap();
+    let yaw = game.start_yaw();
+    let tick = Duration::from_secs_f64(1. / game.replay().tick_rate);
+
+    let mut before = game.run_time();
+    for _ in 0..300 {
+        game.step(&forward(yaw));
+        if game.is_finished() {
+            break;
+        }
+        before = game.run_time();
+    }
+    assert!(game.is_finished());
+
+    // The goal was entered somewhere along the path of the last tick.
+    let finish = game.run_time();
+    assert!(
+        before < finish && finish < before + tick,
+        "{finish:?} not between {before:?} and {:?}",
+        before + tick
+    );
+}
+
+#[test]
+fn clock_counts_simulated_ticks() {
+    let mut game = winter();
+    let start = game.run_time();
+
+    game.hold(&TickInput::default(), 2.);
+
+    let elapsed = (game.run_time() - start).as_secs_f64();
+    assert!((elapsed - 2.).abs() < 1e-6, "elapsed {elapsed}");
+}
+
+#[test]
+fn malformed_map_reports_position() {
+    let Err(MapError::Json { line, .. }) =
+        HeadlessGame::new("{\n  \"name\": \"broken\",\n  oops\n}")
+    else {
+        panic!("Malformed map must fail to parse.");
+    };
+    assert_eq!(line, 3);
+}
+
+#[test]
+fn missing_scene_is_an_error() {
+    let mut map = Map::load("winter").unwrap();
+    map.file = "does_not_exist".into();
+
+    assert!(matches!(
+        HeadlessGame::with_map(map),
+        Err(MapError::MissingScene(_))
+    ));
+}
+
+#[test]
+fn maps_default_to_the_default_profile() {
+    let map = Map::load("winter").unwrap();
+
+    assert_eq!(map.profile, "default");
+    assert_eq!(map.movement, MovementProfile::default());
+}
+
+#[test]
+fn missing_profile_is_an_error() {
+    let json = std::fs::read_to_string("maps/winter").unwrap();
+    let json = json.replacen(
+        '{',
+        "{\n    \"format_version\": 2,\n    \"profile\": \"does_not_exist\",",
+        1,
+    );
+
+    assert!(matches!(
+        HeadlessGame::new(&json),
+        Err(MapError::Profile { .. })
+    ));
+}
+
+#[test]
+fn restarting_drops_tuned_values() {
+    let mut game = winter();
+    let yaw = game.start_yaw();
+    let mut tuned = MovementProfile::default();
+    tuned.acceleration *= 2.;
+
+    game.tune(tuned);
+    game.hold(&forward(yaw), 0.5);
+    assert!(game.is_tuned());
+
+    game.restart();
+    game.hold(&forward(yaw), 0.5);
+    assert!(!game.is_tuned());
+}
+
+#[test]
+fn replay_survives_the_binary_format() {
+    let mut game = winter();
+    let yaw = game.start_yaw();
+    game.hold(&forward(yaw), 1.);
+    game.hold(
+        &TickInput {
+            yaw: yaw + 0.5,
+            actions: vec![MovementAction::Move(Vec3::Z), MovementAction::Jump],
+        },
+        1.,
+    );
+
+    let replay = game.replay();
+    let encoded = replay.encode();
+    let decoded = Replay::decode(&encoded).unwrap();
+
+    assert_eq!(decoded.ticks, replay.ticks);
+    assert_eq!(decoded.track.len(), replay.track.len());
+    assert_eq!(replay.track.len(), replay.ticks.len());
+    for (decoded, recorded) in decoded.track.iter().zip(&replay.track) {
+        assert!(decoded.position.distance(recorded.position) < 0.001);
+        assert!(decoded.rotation.angle_between(recorded.rotation) < 0.01);
+        assert_eq!(decoded.animation, recorded.animation);
+    }
+    // Steady input and movement take a few bytes per tick.
+    assert!(
+        encoded.len() < replay.ticks.len() * 12,
+        "{} bytes",
+        encoded.len()
+    );
+}
+
+#[test]
+fn replay_of_another_version_is_unsupported() {
+    let mut game = winter();
+    game.hold(&forward(game.start_yaw()), 0.5);
+
+    let mut encoded = game.replay().encode();
+    // The format version follows the four magic bytes.
+    encoded[4..8].copy_from_slice(&99u32.to_le_bytes());
+
+    assert!(matches!(
+        Replay::decode(&encoded),
+        Err(DecodeError::UnsupportedVersion(99))
+    ));
+}
+
+#[test]
+fn replay_track_must_match_its_ticks() {
+    let mut game = winter();
+    game.hold(&forward(game.start_yaw()), 0.5);
+
+    let mut replay = game.replay().clone();
+    replay.track.pop();
+
+    assert!(matches!(
+        Replay::decode(&replay.encode()),
+        Err(DecodeError::Invalid(_))
+    ));
+}
+
+#[test]
+fn recorded_replay_resimulates_the_run() {
+    let map = || winter_straight(&[6.], 30.);
+    let mut recorded = HeadlessGame::with_map(map()).unwrap();
+    let yaw = recorded.start_yaw();
+    for tick in 0..240 {
+        let mut input = forward(yaw + (tick as f32 * 0.05).sin() * 0.2);
+        if tick % 50 == 20 {
+            input.actions.push(MovementAction::Jump);
+        }
+        recorded.step(&input);
+        if recorded.is_finished() {
+            break;
+        }
+    }
+    assert!(recorded.is_finished());
+    let replay = recorded.replay().clone();
+    assert!(replay.is_playable_on(&map()));
+    assert_eq!(replay.finish, Some(recorded.run_time()));
+    assert_eq!(
+        Replay::decode(&replay.encode()).unwrap().finish,
+        replay.finish
+    );
+
+    let mut replayed = HeadlessGame::with_map(map()).unwrap();
+    replayed.run_script(&replay.ticks);
+
+    assert!(replayed.is_finished());
+    assert_eq!(replayed.run_time(), recorded.run_time());
+    assert_eq!(replayed.player_position(), recorded.player_position());
+}
+
+#[test]
+fn replay_track_is_sampled_between_ticks() {
+    let mut game = winter();
+    let yaw = game.start_yaw();
+    game.hold(&forward(yaw), 1.);
+
+    let replay = game.replay();
+    let tick = Duration::from_secs_f64(1. / replay.tick_rate);
+    let third = replay.sample(tick * 3).unwrap();
+    let fourth = replay.sample(tick * 4).unwrap();
+    assert!(third.position.distance(replay.track[2].position) < 0.001);
+    assert!(fourth.position.distance(replay.track[3].position) < 0.001);
+
+    let between = replay.sample(tick * 3 + tick / 2).unwrap();
+    let midpoint = third.position.lerp(fourth.position, 0.5);
+    assert!(between.position.distance(midpoint) < 0.001);
+
+    // Past the end the character stays where the run ended.
+    let end = replay.sample(replay.duration() * 2).unwrap();
+    assert_eq!(end.position, replay.track.last().unwrap().position);
+}
+
+#[test]
+fn replay_tells_when_it_passed_a_position() {
+    let mut game = winter();
+    let yaw = game.start_yaw();
+    game.hold(&forward(yaw), 2.);
+
+    let replay = game.replay();
+    for seconds in [0.5, 1., 1.5] {
+        let time = Duration::from_secs_f64(seconds);
+        let position = replay.sample(time).unwrap().position;
+        let passed = replay.time_at(position).unwrap();
+        assert!(
+            (passed.as_secs_f64() - seconds).abs() < 0.02,
+            "{passed:?} instead of {seconds}"
+        );
+    }
+}